@@ -5,16 +5,306 @@
 
 // use anyhow::Result;
 
+mod de;
+mod path;
+mod ser;
+
+pub use de::{from_bytes, from_value};
+pub use path::{Location, PathError};
+pub use ser::{to_bytes, to_value};
+
 use serde_json::{Map, Value};
-use sha1::{Digest, Sha1};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+use thiserror::Error;
+
+/// A decoded bencode value.
+///
+/// Byte strings are kept as raw bytes, so binary data such as `pieces` or
+/// compact peer lists survives decoding untouched. Dictionary keys are byte
+/// strings as well and are kept sorted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BValue {
+    Int(i64),
+    /// An integer outside the `i64` range, holding its digits (and sign)
+    /// exactly as they were encoded. Integers that fit are always `Int`.
+    BigInt(String),
+    Bytes(Vec<u8>),
+    List(Vec<BValue>),
+    Dict(BTreeMap<Vec<u8>, BValue>),
+}
+
+impl BValue {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            BValue::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Digits of any integer, whether or not it fits in an `i64`.
+    pub fn as_integer_digits(&self) -> Option<std::borrow::Cow<'_, str>> {
+        match self {
+            BValue::Int(n) => Some(n.to_string().into()),
+            BValue::BigInt(digits) => Some(digits.as_str().into()),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BValue::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Byte string as `&str`, if it is valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes()
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[BValue]> {
+        match self {
+            BValue::List(vec) => Some(vec),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, BValue>> {
+        match self {
+            BValue::Dict(map) => Some(map),
+            _ => None,
+        }
+    }
+
+    /// Look up `key` when this value is a dictionary.
+    pub fn get(&self, key: &str) -> Option<&BValue> {
+        self.as_dict().and_then(|map| map.get(key.as_bytes()))
+    }
+
+    /// Convert into JSON for display.
+    ///
+    /// Byte strings that are not valid UTF-8 are shown as lowercase hex,
+    /// so the conversion is lossy; use the `BValue` itself to get the bytes.
+    pub fn to_json(&self) -> Value {
+        match self {
+            BValue::Int(n) => Value::Number((*n).into()),
+            // JSON numbers here are limited to 64 bits
+            BValue::BigInt(digits) => match digits.parse::<u64>() {
+                Ok(n) => Value::Number(n.into()),
+                Err(_) => Value::String(digits.clone()),
+            },
+            BValue::Bytes(bytes) => Value::String(bytes_to_display(bytes)),
+            BValue::List(vec) => Value::Array(vec.iter().map(BValue::to_json).collect()),
+            BValue::Dict(map) => Value::Object(
+                map.iter()
+                    .map(|(k, v)| (bytes_to_display(k), v.to_json()))
+                    .collect::<Map<String, Value>>(),
+            ),
+        }
+    }
+
+    /// Convert from JSON. Strings become byte strings and objects become
+    /// dictionaries; `null`, booleans and non-integer numbers have no bencode
+    /// representation and are rejected.
+    pub fn from_json(value: &Value) -> Result<BValue, BencodeError> {
+        match value {
+            Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+                (Some(n), _) => Ok(BValue::Int(n)),
+                (None, Some(n)) => Ok(BValue::BigInt(n.to_string())),
+                _ => Err(BencodeError::Unsupported { kind: "number" }),
+            },
+            Value::String(s) => Ok(BValue::Bytes(s.as_bytes().to_vec())),
+            Value::Array(vec) => vec
+                .iter()
+                .map(BValue::from_json)
+                .collect::<Result<Vec<_>, _>>()
+                .map(BValue::List),
+            Value::Object(map) => map
+                .iter()
+                .map(|(k, v)| BValue::from_json(v).map(|v| (k.as_bytes().to_vec(), v)))
+                .collect::<Result<BTreeMap<_, _>, _>>()
+                .map(BValue::Dict),
+            Value::Null => Err(BencodeError::Unsupported { kind: "null" }),
+            Value::Bool(_) => Err(BencodeError::Unsupported { kind: "boolean" }),
+        }
+    }
+
+    /// Encode as canonical bencode: integers without leading zeros and
+    /// dictionary keys in sorted order.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_to(&mut out);
+        out
+    }
+
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            BValue::Int(n) => {
+                out.push(b'i');
+                out.extend_from_slice(n.to_string().as_bytes());
+                out.push(b'e');
+            }
+            BValue::BigInt(digits) => {
+                out.push(b'i');
+                out.extend_from_slice(digits.as_bytes());
+                out.push(b'e');
+            }
+            BValue::Bytes(bytes) => encode_bytes(bytes, out),
+            BValue::List(vec) => {
+                out.push(b'l');
+                vec.iter().for_each(|v| v.encode_to(out));
+                out.push(b'e');
+            }
+            BValue::Dict(map) => {
+                out.push(b'd');
+                map.iter().for_each(|(k, v)| {
+                    encode_bytes(k, out);
+                    v.encode_to(out);
+                });
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+pub trait ToBencode {
+    fn to_bencode(&self) -> Result<Vec<u8>, BencodeError>;
+}
+
+impl ToBencode for BValue {
+    fn to_bencode(&self) -> Result<Vec<u8>, BencodeError> {
+        Ok(self.encode())
+    }
+}
+
+impl ToBencode for Value {
+    fn to_bencode(&self) -> Result<Vec<u8>, BencodeError> {
+        BValue::from_json(self).map(|value| value.encode())
+    }
+}
+
+impl fmt::Display for BValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_json())
+    }
+}
+
+/// Name under which a [`BValue::BigInt`] travels through serde as a
+/// newtype struct holding its digits, so that they are never rounded or
+/// rejected.
+pub(crate) const BIG_INT_MARKER: &str = "$bencode::private::BigInt";
+
+/// An optional `-` followed by at least one digit.
+pub(crate) fn is_integer_digits(digits: &[u8]) -> bool {
+    let magnitude = digits.strip_prefix(b"-").unwrap_or(digits);
+    !magnitude.is_empty() && magnitude.iter().all(u8::is_ascii_digit)
+}
+
+fn bytes_to_display(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.to_string(),
+        Err(_) => hex::encode(bytes),
+    }
+}
+
+/// Errors produced while decoding bencode.
+///
+/// Offsets are byte positions in the input that was handed to the decoder.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum BencodeError {
+    #[error("unexpected end of input at byte {offset}, expected {expected}")]
+    UnexpectedEof {
+        offset: usize,
+        expected: &'static str,
+    },
+    #[error("invalid bencode at byte {offset}, expected {expected}, found `{excerpt}`")]
+    Syntax {
+        offset: usize,
+        expected: &'static str,
+        excerpt: String,
+    },
+    #[error("non-canonical bencode at byte {offset}: {kind}")]
+    NonCanonical { offset: usize, kind: ViolationKind },
+    #[error("{limit} exceeded at byte {offset}")]
+    LimitExceeded { offset: usize, limit: &'static str },
+    #[error("{kind} has no bencode representation")]
+    Unsupported { kind: &'static str },
+    #[error("missing dictionary key `{key}`")]
+    MissingKey { key: String },
+    #[error("{0}")]
+    Custom(String),
+}
+
+impl BencodeError {
+    /// Byte offset at which decoding failed, if the error came from decoding.
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            BencodeError::UnexpectedEof { offset, .. }
+            | BencodeError::Syntax { offset, .. }
+            | BencodeError::NonCanonical { offset, .. }
+            | BencodeError::LimitExceeded { offset, .. } => Some(*offset),
+            BencodeError::Unsupported { .. }
+            | BencodeError::MissingKey { .. }
+            | BencodeError::Custom(_) => None,
+        }
+    }
+}
+
+/// Ways in which input can depart from canonical bencode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum ViolationKind {
+    #[error("number with a leading zero")]
+    LeadingZero,
+    #[error("negative zero")]
+    NegativeZero,
+    #[error("negative string length")]
+    NegativeLength,
+    #[error("dictionary key out of order")]
+    UnsortedKey,
+    #[error("duplicate dictionary key")]
+    DuplicateKey,
+    #[error("trailing data after the value")]
+    TrailingData,
+}
+
+/// A single non-canonical construct found in the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation {
+    pub offset: usize,
+    pub kind: ViolationKind,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "byte {}: {}", self.offset, self.kind)
+    }
+}
+
+/// Number of input bytes quoted in `BencodeError::Syntax`.
+const EXCERPT_LEN: usize = 16;
+
+fn excerpt(input: &[u8], offset: usize) -> String {
+    let end = usize::min(input.len(), offset + EXCERPT_LEN);
+    input[offset.min(end)..end].escape_ascii().to_string()
+}
 
 pub trait Bencode {
-    fn bdecode(&self) -> Value;
-    fn bdecode_each(&self) -> (Value, &Self);
-    fn bdecode_integer(&self) -> (Value, &Self);
-    fn bdecode_string(&self) -> (Value, &Self);
-    fn bdecode_dictionary(&self) -> (Value, &Self);
-    fn bdecode_list(&self) -> (Value, &Self);
+    /// Decode a single value, ignoring any bytes after it. Use
+    /// [`Bencode::bdecode_each`] to see them, or [`decode_strict`] to reject
+    /// them.
+    fn bdecode(&self) -> Result<BValue, BencodeError>;
+    /// Decode a single value and return it together with the remaining input.
+    fn bdecode_each(&self) -> Result<(BValue, &Self), BencodeError>;
 }
 
 // fn test() -> Result<()>
@@ -22,284 +312,713 @@ pub trait Bencode {
 //     Ok(())
 // }
 
+/// Text input is decoded byte for byte, exactly like the equivalent `[u8]`;
+/// string lengths count bytes, not characters.
 impl Bencode for str {
-    fn bdecode(&self) -> Value {
-        let (value, encoded_remain) = self.bdecode_each();
-        if !encoded_remain.is_empty() {
-            eprintln!("There is remaining encoded value : {}", encoded_remain);
-        }
-        value
-    }
-
-    fn bdecode_each(&self) -> (Value, &str) {
-        let first = self.chars().next();
-
-        match first {
-            Some('i') => return self.bdecode_integer(),
-            Some('l') => return self.bdecode_list(),
-            Some('d') => return self.bdecode_dictionary(),
-            Some(c) => {
-                if c.is_ascii_digit() {
-                    return self.bdecode_string();
-                } else {
-                    panic!("Unhandled encoded integer value: {}", self)
+    fn bdecode(&self) -> Result<BValue, BencodeError> {
+        self.as_bytes().bdecode()
+    }
+
+    fn bdecode_each(&self) -> Result<(BValue, &str), BencodeError> {
+        let (value, rest) = self.as_bytes().bdecode_each()?;
+        let offset = self.len() - rest.len();
+        match self.get(offset..) {
+            Some(rest) => Ok((value, rest)),
+            None => Err(BencodeError::Syntax {
+                offset,
+                expected: "a value ending on a character boundary",
+                excerpt: excerpt(self.as_bytes(), offset),
+            }),
+        }
+    }
+}
+
+impl Bencode for [u8] {
+    fn bdecode(&self) -> Result<BValue, BencodeError> {
+        self.bdecode_each().map(|(value, _)| value)
+    }
+
+    fn bdecode_each(&self) -> Result<(BValue, &[u8]), BencodeError> {
+        let mut decoder = Decoder::new(self);
+        let value = decoder.decode_value()?;
+        Ok((value, decoder.remaining()))
+    }
+}
+
+/// Raw encoded bytes of the value at `path` (a list of dictionary keys) in
+/// `input`, exactly as they appear there.
+///
+/// This is what hashes such as the info hash must be computed over, since
+/// re-encoding a decoded value is not guaranteed to reproduce the input.
+/// Pass [`Limits::torrent_file`] for local `.torrent` files, whose `pieces`
+/// string is often larger than the untrusted default allows.
+pub fn raw_value<'a>(
+    input: &'a [u8],
+    path: &[&str],
+    limits: Limits,
+) -> Result<&'a [u8], BencodeError> {
+    let options = DecodeOptions {
+        limits,
+        ..DecodeOptions::default()
+    };
+    let span = Decoder::with_options(input, options).find_span(path)?;
+    Ok(&input[span])
+}
+
+/// Decode `input` as a single canonical bencoded value, rejecting anything
+/// `lint` would report.
+pub fn decode_strict(input: &[u8]) -> Result<BValue, BencodeError> {
+    let options = DecodeOptions {
+        strict: true,
+        ..DecodeOptions::default()
+    };
+    Decoder::with_options(input, options).decode_all()
+}
+
+/// Outcome of [`lint`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    /// Every non-canonical construct found, in input order.
+    pub violations: Vec<Violation>,
+    /// Set when the input could not be decoded at all; violations after this
+    /// point are unknown.
+    pub error: Option<BencodeError>,
+}
+
+impl Lint {
+    pub fn is_clean(&self) -> bool {
+        self.violations.is_empty() && self.error.is_none()
+    }
+}
+
+/// Check `input` for canonical encoding, collecting every violation instead
+/// of stopping at the first one.
+pub fn lint(input: &[u8], limits: Limits) -> Lint {
+    let options = DecodeOptions {
+        limits,
+        ..DecodeOptions::default()
+    };
+    let mut decoder = Decoder::with_options(input, options);
+    let error = decoder.decode_all().err();
+    Lint {
+        violations: decoder.violations,
+        error,
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// Fail on the first non-canonical construct instead of recording it.
+    pub strict: bool,
+    pub limits: Limits,
+}
+
+/// Bounds on the resources a single decode may use.
+///
+/// The default is sized for untrusted network input such as tracker
+/// responses and peer extension messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Deepest allowed nesting of lists and dictionaries.
+    pub max_depth: usize,
+    /// Longest allowed byte string.
+    pub max_string_len: usize,
+    /// Most values (of any type, at any depth) in one input.
+    pub max_items: usize,
+    /// Largest input accepted at all.
+    pub max_input_len: usize,
+}
+
+impl Limits {
+    /// Limits for peers and trackers.
+    pub fn untrusted() -> Self {
+        Limits {
+            max_depth: 32,
+            max_string_len: 1 << 20,
+            max_items: 100_000,
+            max_input_len: 4 << 20,
+        }
+    }
+
+    /// Limits for local `.torrent` files, whose `pieces` string alone can
+    /// run to megabytes.
+    pub fn torrent_file() -> Self {
+        Limits {
+            max_depth: 256,
+            max_string_len: 128 << 20,
+            max_items: 10_000_000,
+            max_input_len: 256 << 20,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits::untrusted()
+    }
+}
+
+/// Outcome of feeding bytes to a [`StreamDecoder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Progress {
+    /// The buffered bytes are a valid but incomplete prefix of a value.
+    NeedMoreData,
+    /// A value was decoded from the first `consumed` buffered bytes.
+    Complete { value: BValue, consumed: usize },
+}
+
+/// Push-style decoder for input that arrives in chunks, such as HTTP
+/// tracker bodies.
+///
+/// Each byte is scanned once, so feeding a value in many small chunks costs
+/// no more than decoding it whole, and a prefix that can never become valid
+/// bencode is rejected as soon as the offending byte arrives. After a value
+/// completes, any bytes that followed it stay buffered: they are either the
+/// start of the next value or a raw payload that trails it.
+#[derive(Debug, Default)]
+pub struct StreamDecoder {
+    buffer: Vec<u8>,
+    options: DecodeOptions,
+    scan: Scan,
+}
+
+/// How far [`StreamDecoder`] got through its buffer.
+#[derive(Debug, Default)]
+struct Scan {
+    /// Buffered bytes already scanned.
+    pos: usize,
+    /// Open lists and dictionaries, innermost last.
+    open: Vec<Open>,
+    token: Token,
+    items: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Open {
+    List,
+    /// A dictionary expecting a key (or its end) next.
+    DictKey,
+    /// A dictionary expecting the value for the key just read.
+    DictValue,
+}
+
+/// A scalar cut off by the end of the buffer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    /// Between values.
+    #[default]
+    None,
+    /// After `i`.
+    Int { negative: bool, digits: usize },
+    /// Inside a string's length prefix.
+    Length { len: usize },
+    /// Inside a string's contents.
+    Contents { remaining: usize },
+}
+
+/// What scanning the buffer found.
+enum Scanned {
+    Incomplete,
+    /// A value ends after this many bytes.
+    Complete(usize),
+    /// The byte at this offset cannot continue any value.
+    Invalid(usize),
+}
+
+impl Scan {
+    fn scan(&mut self, input: &[u8], limits: &Limits) -> Result<Scanned, BencodeError> {
+        while self.pos < input.len() {
+            let byte = input[self.pos];
+            match self.token {
+                Token::Contents { remaining } => {
+                    let take = remaining.min(input.len() - self.pos);
+                    self.pos += take;
+                    self.token = Token::Contents {
+                        remaining: remaining - take,
+                    };
+                    if remaining == take && self.finish_value() {
+                        return Ok(Scanned::Complete(self.pos));
+                    }
+                    continue;
+                }
+                Token::Int { negative, digits } => match byte {
+                    b'-' if !negative && digits == 0 => {
+                        self.token = Token::Int {
+                            negative: true,
+                            digits,
+                        }
+                    }
+                    b'0'..=b'9' => {
+                        self.token = Token::Int {
+                            negative,
+                            digits: digits + 1,
+                        }
+                    }
+                    b'e' if digits > 0 => {
+                        self.pos += 1;
+                        if self.finish_value() {
+                            return Ok(Scanned::Complete(self.pos));
+                        }
+                        continue;
+                    }
+                    _ => return Ok(Scanned::Invalid(self.pos)),
+                },
+                Token::Length { len } => match byte {
+                    b'0'..=b'9' => {
+                        let len = len
+                            .checked_mul(10)
+                            .and_then(|len| len.checked_add((byte - b'0') as usize))
+                            .filter(|len| *len <= limits.max_string_len);
+                        let Some(len) = len else {
+                            return Err(self.limit_exceeded("maximum string length"));
+                        };
+                        self.token = Token::Length { len };
+                    }
+                    b':' => {
+                        self.pos += 1;
+                        self.token = Token::Contents { remaining: len };
+                        if len == 0 && self.finish_value() {
+                            return Ok(Scanned::Complete(self.pos));
+                        }
+                        continue;
+                    }
+                    _ => return Ok(Scanned::Invalid(self.pos)),
+                },
+                Token::None => {
+                    let open = self.open.last().copied();
+                    if byte == b'e' && matches!(open, Some(Open::List | Open::DictKey)) {
+                        self.open.pop();
+                        self.pos += 1;
+                        if self.finish_value() {
+                            return Ok(Scanned::Complete(self.pos));
+                        }
+                        continue;
+                    }
+                    if open == Some(Open::DictKey) && !byte.is_ascii_digit() {
+                        return Ok(Scanned::Invalid(self.pos));
+                    }
+                    if open != Some(Open::DictKey) {
+                        self.items += 1;
+                        if self.items > limits.max_items {
+                            return Err(self.limit_exceeded("maximum item count"));
+                        }
+                    }
+                    match byte {
+                        b'i' => {
+                            self.token = Token::Int {
+                                negative: false,
+                                digits: 0,
+                            }
+                        }
+                        b'l' | b'd' => {
+                            if self.open.len() >= limits.max_depth {
+                                return Err(self.limit_exceeded("maximum nesting depth"));
+                            }
+                            self.open.push(if byte == b'l' {
+                                Open::List
+                            } else {
+                                Open::DictKey
+                            });
+                        }
+                        b'0'..=b'9' => {
+                            self.token = Token::Length {
+                                len: (byte - b'0') as usize,
+                            }
+                        }
+                        _ => return Ok(Scanned::Invalid(self.pos)),
+                    }
                 }
             }
-            None => panic!("There is no argument"),
+            self.pos += 1;
+        }
+        if input.len() > limits.max_input_len {
+            return Err(BencodeError::LimitExceeded {
+                offset: 0,
+                limit: "maximum input size",
+            });
         }
+        Ok(Scanned::Incomplete)
     }
 
-    fn bdecode_dictionary(&self) -> (Value, &str) {
-        let mut map = Map::new();
-        let mut en_value = &self[1..];
+    /// Close the scalar or container that just ended; true when it was the
+    /// top-level value.
+    fn finish_value(&mut self) -> bool {
+        self.token = Token::None;
+        match self.open.last_mut() {
+            None => return true,
+            Some(open @ Open::DictKey) => *open = Open::DictValue,
+            Some(open @ Open::DictValue) => *open = Open::DictKey,
+            Some(Open::List) => {}
+        }
+        false
+    }
 
-        while !en_value.starts_with('e') {
-            let (key, en_value_) = en_value.bdecode_each();
-            let (value, en_value_) = en_value_.bdecode_each();
-            en_value = en_value_;
+    fn limit_exceeded(&self, limit: &'static str) -> BencodeError {
+        BencodeError::LimitExceeded {
+            offset: self.pos,
+            limit,
+        }
+    }
+}
 
-            if let serde_json::Value::String(s) = key {
-                map.insert(s, value);
-            } else {
-                panic!("key has to be a string");
-            }
+impl StreamDecoder {
+    pub fn new() -> Self {
+        StreamDecoder::default()
+    }
+
+    pub fn with_options(options: DecodeOptions) -> Self {
+        StreamDecoder {
+            options,
+            ..StreamDecoder::default()
         }
-        (serde_json::Value::Object(map), &en_value[1..])
-    }
-
-    fn bdecode_list(&self) -> (Value, &str) {
-        let mut vec: Vec<Value> = Vec::new();
-        let mut en_value = &self[1..];
-
-        while let Some(c) = en_value.chars().next() {
-            // println!("c = {}, en_value = {}", c, en_value);
-            if c.is_ascii_digit() {
-                // println!("String");
-                let (value, encoded_next) = en_value.bdecode_string();
-                vec.push(value);
-                en_value = encoded_next;
-            } else if c == 'i' {
-                // println!("Integer");
-                let (value, encoded_next) = en_value.bdecode_integer();
-                vec.push(value);
-                en_value = encoded_next;
-            } else if c == 'l' {
-                // println!("List");
-                let (value, encoded_next) = en_value.bdecode_list();
-                vec.push(value);
-                en_value = encoded_next;
-            } else if c == 'd' {
-                // println!("Dictionary");
-                let (value, encoded_next) = en_value.bdecode_dictionary();
-                vec.push(value);
-                en_value = encoded_next;
-            } else if c == 'e' {
-                return (Value::Array(vec), &en_value[1..]);
-            } else {
-                panic!("Unhandled encoded chacter: {}", c);
-            }
+    }
+
+    /// Append `bytes` and try to decode one value from the buffer.
+    pub fn push(&mut self, bytes: &[u8]) -> Result<Progress, BencodeError> {
+        self.buffer.extend_from_slice(bytes);
+        self.poll()
+    }
 
-            if en_value.is_empty() {
-                panic!("There is no the ending 'e' symbol for outer list");
+    /// Try to decode one value from what is already buffered, picking up
+    /// where the last call stopped.
+    pub fn poll(&mut self) -> Result<Progress, BencodeError> {
+        let scanned = self.scan.scan(&self.buffer, &self.options.limits)?;
+        let end = match scanned {
+            Scanned::Incomplete => return Ok(Progress::NeedMoreData),
+            Scanned::Complete(end) => end,
+            // decode up to and including the bad byte for a precise error
+            Scanned::Invalid(offset) => offset + 1,
+        };
+        self.scan = Scan::default();
+        let mut decoder = Decoder::with_options(&self.buffer[..end], self.options);
+        match (scanned, decoder.decode_value()) {
+            (Scanned::Complete(_), Ok(value)) => {
+                self.buffer.drain(..end);
+                Ok(Progress::Complete {
+                    value,
+                    consumed: end,
+                })
             }
+            (Scanned::Invalid(offset), Err(BencodeError::UnexpectedEof { .. }) | Ok(_)) => {
+                Err(decoder.error(offset, "a bencoded value"))
+            }
+            (_, Err(err)) => Err(err),
+            (_, Ok(_)) => unreachable!("only complete values are decoded"),
         }
-        panic!("Unhandled encoded value: {}", en_value);
     }
 
-    fn bdecode_string(&self) -> (Value, &str) {
-        let colon_index = self.find(':').unwrap();
-        let number_string = &self[..colon_index];
-        let number = number_string.parse::<i64>().unwrap();
-        let string = &self[colon_index + 1..colon_index + 1 + number as usize];
-        // consider storing it as Vec<u8>
-        (
-            Value::String(string.to_string()),
-            &self[colon_index + 1 + number as usize..],
-        )
+    /// Bytes received but not yet decoded.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
     }
 
-    fn bdecode_integer(&self) -> (Value, &str) {
-        let end_index = self.find('e').unwrap();
-        let number_string = &self[1..end_index];
-        let number = number_string.parse::<i64>().unwrap();
-        (Value::Number(number.into()), &self[end_index + 1..])
+    /// Take the undecoded bytes out of the decoder, leaving it empty.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        self.scan = Scan::default();
+        std::mem::take(&mut self.buffer)
     }
 }
 
-impl Bencode for [u8] {
-    fn bdecode(&self) -> Value {
-        let (value, encoded_remain) = self.bdecode_each();
-        if !encoded_remain.is_empty() {
-            eprintln!(
-                "There is remaining encoded value, its length : {}",
-                encoded_remain.len()
-            );
+/// Byte-oriented bencode decoder.
+///
+/// Walks the input with a cursor so every error can point at the absolute
+/// offset where decoding went wrong. Non-canonical input is accepted and
+/// recorded in [`Decoder::violations`] unless the decoder is strict.
+pub struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    options: DecodeOptions,
+    violations: Vec<Violation>,
+    depth: usize,
+    items: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Decoder::with_options(input, DecodeOptions::default())
+    }
+
+    pub fn with_options(input: &'a [u8], options: DecodeOptions) -> Self {
+        Decoder {
+            input,
+            pos: 0,
+            options,
+            violations: Vec::new(),
+            depth: 0,
+            items: 0,
         }
-        value
-    }
-    fn bdecode_each(&self) -> (Value, &[u8]) {
-        let first = self.iter().next();
-
-        match first {
-            Some(b'i') => return self.bdecode_integer(),
-            Some(b'l') => return self.bdecode_list(),
-            Some(b'd') => return self.bdecode_dictionary(),
-            Some(&b) => {
-                let c = b as char;
-                if c.is_ascii_digit() {
-                    return self.bdecode_string();
-                } else {
-                    panic!(
-                        "Unhandled encoded integer value, its length : {}",
-                        self.len()
-                    )
-                }
+    }
+
+    /// Non-canonical constructs seen so far.
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    /// Offset of the next byte to be decoded.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Input that has not been decoded yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.input[self.pos..]
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn violation(&mut self, offset: usize, kind: ViolationKind) -> Result<(), BencodeError> {
+        if self.options.strict {
+            return Err(BencodeError::NonCanonical { offset, kind });
+        }
+        self.violations.push(Violation { offset, kind });
+        Ok(())
+    }
+
+    fn limit_exceeded(&self, limit: &'static str) -> BencodeError {
+        BencodeError::LimitExceeded {
+            offset: self.pos,
+            limit,
+        }
+    }
+
+    /// Account for one more value starting at the cursor.
+    fn count_item(&mut self) -> Result<(), BencodeError> {
+        let limits = self.options.limits;
+        if self.input.len() > limits.max_input_len {
+            return Err(BencodeError::LimitExceeded {
+                offset: 0,
+                limit: "maximum input size",
+            });
+        }
+        self.items += 1;
+        if self.items > limits.max_items {
+            return Err(self.limit_exceeded("maximum item count"));
+        }
+        Ok(())
+    }
+
+    fn enter(&mut self) -> Result<(), BencodeError> {
+        self.depth += 1;
+        if self.depth > self.options.limits.max_depth {
+            return Err(self.limit_exceeded("maximum nesting depth"));
+        }
+        Ok(())
+    }
+
+    /// Check the digits of an integer or string length for canonical form.
+    fn check_digits(&mut self, start: usize, digits: &[u8]) -> Result<(), BencodeError> {
+        match digits {
+            [b'-', b'0', ..] if digits.len() == 2 => {
+                self.violation(start, ViolationKind::NegativeZero)
             }
-            None => panic!("There is no argument"),
+            [b'-', b'0', ..] | [b'0', _, ..] => self.violation(start, ViolationKind::LeadingZero),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check a position where a string is expected but a `-` was found.
+    fn negative_length(&self) -> Result<(), BencodeError> {
+        let rest = &self.input[self.pos..];
+        if rest.len() > 1 && rest[0] == b'-' && rest[1].is_ascii_digit() {
+            return Err(BencodeError::NonCanonical {
+                offset: self.pos,
+                kind: ViolationKind::NegativeLength,
+            });
         }
+        Ok(())
     }
-    fn bdecode_integer(&self) -> (Value, &[u8]) {
-        let mut iter = self.iter();
-        if let Some(&b) = iter.next() {
-            if b != b'i' {
-                panic!("It's not for integer");
+
+    fn error(&self, offset: usize, expected: &'static str) -> BencodeError {
+        if offset >= self.input.len() {
+            BencodeError::UnexpectedEof { offset, expected }
+        } else {
+            BencodeError::Syntax {
+                offset,
+                expected,
+                excerpt: excerpt(self.input, offset),
             }
         }
-        let number_counter = iter.take_while(|&&b| b != b'e').count();
-        let number_string = String::from_utf8((&self[1..number_counter + 1]).into()).unwrap();
-        let number = number_string.parse::<i64>().unwrap();
-        (Value::Number(number.into()), &self[number_counter + 2..])
     }
-    fn bdecode_string(&self) -> (Value, &[u8]) {
-        let colon_index = self
+
+    /// Advance up to (not past) the next `byte`, returning the skipped bytes.
+    fn take_until(&mut self, byte: u8, expected: &'static str) -> Result<&'a [u8], BencodeError> {
+        let start = self.pos;
+        let len = self.input[start..]
             .iter()
-            // .inspect(|&&b| eprintln!("{}", b as char))
-            .take_while(|&&b| b != b':')
-            .count();
-        let number_string = String::from_utf8((&self[..colon_index]).into()).unwrap();
-        // eprintln!("number_string {}", number_string);
-        let number = number_string.parse::<i64>().unwrap();
-        if let Ok(string) =
-            String::from_utf8((&self[colon_index + 1..colon_index + 1 + number as usize]).into())
-        {
-            (
-                Value::String(string.to_string()),
-                &self[colon_index + 1 + number as usize..],
-            )
-        } else {
-            // hexadecimal representation
-            let pieces = &self[colon_index + 1..colon_index + 1 + number as usize];
-            let string: String = pieces.iter().map(|b| format!("{:02x}", b)).collect();
-            (
-                Value::String(string.to_string()),
-                &self[colon_index + 1 + number as usize..],
-            )
-        }
-    }
-    fn bdecode_dictionary(&self) -> (Value, &[u8]) {
-        let mut map = Map::new();
-        let mut en_value = &self[1..];
-
-        while Some(&b'e') != en_value.iter().next() {
-            let (key, en_value_) = en_value.bdecode_each();
-            en_value = en_value_;
-            // eprintln!("key = {}", key);
-            if let Value::String(s) = key {
-                if s == "info" {
-                    let mut hasher = Sha1::new();
-                    hasher.update(&en_value[..en_value.len() - 1]);
-                    let result = hasher.finalize();
-                    let info_hash: String = result.iter().map(|b| format!("{:02x}", b)).collect();
-                    map.insert("info hash".to_string(), Value::String(info_hash));
-                }
-                // if s == "pieces" || s == "peer id" {
-                if s == "pieces" {
-                    let (value, en_value_) = en_value.bdecode_string();
-                    en_value = en_value_;
-                    map.insert(s, value);
-                } else if s == "peers" {
-                    let colon_index = en_value
-                        .iter()
-                        // .inspect(|&&b| eprintln!("{}", b as char))
-                        .take_while(|&&b| b != b':')
-                        .count();
-                    let number_string =
-                        String::from_utf8((&en_value[..colon_index]).into()).unwrap();
-                    // eprintln!("number_string {}", number_string);
-                    let number = match number_string.parse::<i64>() {
-                        Ok(num) => num,
-                        Err(err) => panic!(
-                            "parsing number of chacters is failed, number_string = {}\n{}",
-                            number_string, err
-                        ),
-                    };
-                    // let number = number_string.parse::<i64>().unwrap();
-                    let ips = &en_value[colon_index + 1..colon_index + 1 + number as usize];
-
-                    let mut peers_ip: Vec<Value> = Vec::new();
-                    ips.chunks(6).for_each(|arr| {
-                        let mut map = Map::new();
-                        let ip = arr[..4]
-                            .iter()
-                            .map(|b| b.to_string())
-                            .collect::<Vec<String>>()
-                            .join(".");
-                        let port = u16::from_be_bytes([arr[4], arr[5]]);
-                        map.insert("ip".to_string(), Value::String(ip));
-                        map.insert("port".to_string(), Value::Number(port.into()));
-                        peers_ip.push(Value::Object(map));
-                    });
-
-                    en_value = &en_value[colon_index + 1 + number as usize..];
-                    map.insert(s, Value::Array(peers_ip));
-                } else {
-                    let (value, en_value_) = en_value.bdecode_each();
-                    en_value = en_value_;
-                    map.insert(s, value);
-                }
-            } else {
-                panic!("key has to be a string");
+            .position(|&b| b == byte)
+            .ok_or_else(|| self.error(self.input.len(), expected))?;
+        self.pos += len;
+        Ok(&self.input[start..self.pos])
+    }
+
+    /// Decode one value that must span the rest of the input.
+    pub fn decode_all(&mut self) -> Result<BValue, BencodeError> {
+        let value = self.decode_value()?;
+        if self.pos < self.input.len() {
+            self.violation(self.pos, ViolationKind::TrailingData)?;
+        }
+        Ok(value)
+    }
+
+    /// Decode the next value and return it with the byte range it occupies
+    /// in the input.
+    pub fn decode_spanned(&mut self) -> Result<(BValue, Range<usize>), BencodeError> {
+        let start = self.pos;
+        let value = self.decode_value()?;
+        Ok((value, start..self.pos))
+    }
+
+    /// Byte range of the value reached by walking `path` through nested
+    /// dictionaries, starting from the next value in the input.
+    pub fn find_span(&mut self, path: &[&str]) -> Result<Range<usize>, BencodeError> {
+        let Some((key, rest)) = path.split_first() else {
+            return self.decode_spanned().map(|(_, span)| span);
+        };
+        if self.peek() != Some(b'd') {
+            return Err(self.error(self.pos, "a dictionary"));
+        }
+        self.pos += 1; // 'd'
+
+        while self.peek() != Some(b'e') {
+            if !self.peek().is_some_and(|b| b.is_ascii_digit()) {
+                return Err(self.error(self.pos, "a string key or 'e'"));
+            }
+            if self.decode_bytes()? == key.as_bytes() {
+                return self.find_span(rest);
             }
+            self.decode_value()?;
+        }
+        Err(BencodeError::MissingKey {
+            key: key.to_string(),
+        })
+    }
+
+    /// Decode the next value and, when it is a dictionary, the byte range
+    /// of its entry under `key`, in a single pass.
+    pub fn decode_with_span_of(
+        &mut self,
+        key: &str,
+    ) -> Result<(BValue, Option<Range<usize>>), BencodeError> {
+        if self.peek() != Some(b'd') {
+            return self.decode_value().map(|value| (value, None));
         }
-        (serde_json::Value::Object(map), &en_value[1..])
-    }
-
-    fn bdecode_list(&self) -> (Value, &[u8]) {
-        let mut vec: Vec<Value> = Vec::new();
-        let mut en_value = &self[1..];
-
-        while let Some(&b) = en_value.iter().next() {
-            if (b as char).is_ascii_digit() {
-                // println!("String");
-                let (value, encoded_next) = en_value.bdecode_string();
-                vec.push(value);
-                en_value = encoded_next;
-            } else if b == b'i' {
-                // println!("Integer");
-                let (value, encoded_next) = en_value.bdecode_integer();
-                vec.push(value);
-                en_value = encoded_next;
-            } else if b == b'l' {
-                // println!("List");
-                let (value, encoded_next) = en_value.bdecode_list();
-                vec.push(value);
-                en_value = encoded_next;
-            } else if b == b'd' {
-                // println!("Dictionary");
-                let (value, encoded_next) = en_value.bdecode_dictionary();
-                vec.push(value);
-                en_value = encoded_next;
-            } else if b == b'e' {
-                return (Value::Array(vec), &en_value[1..]);
-            } else {
-                panic!("Unhandled encoded chacter: {}", b as char);
+        self.count_item()?;
+        let mut span = None;
+        let value = self.decode_dictionary(Some((key.as_bytes(), &mut span)))?;
+        Ok((value, span))
+    }
+
+    pub fn decode_value(&mut self) -> Result<BValue, BencodeError> {
+        self.count_item()?;
+        match self.peek() {
+            Some(b'i') => self.decode_integer(),
+            Some(b'l') => self.decode_list(),
+            Some(b'd') => self.decode_dictionary(None),
+            Some(b) if b.is_ascii_digit() => self.decode_string(),
+            _ => {
+                self.negative_length()?;
+                Err(self.error(self.pos, "a bencoded value"))
             }
+        }
+    }
 
-            if en_value.is_empty() {
-                panic!("There is no the ending 'e' symbol for outer list");
+    fn decode_integer(&mut self) -> Result<BValue, BencodeError> {
+        self.pos += 1; // 'i'
+        let start = self.pos;
+        let digits = self.take_until(b'e', "'e'")?;
+        if !is_integer_digits(digits) {
+            return Err(self.error(start, "an integer"));
+        }
+        self.check_digits(start, digits)?;
+        self.pos += 1; // 'e'
+
+        // digits are ASCII, so this cannot fail
+        let digits = std::str::from_utf8(digits).unwrap_or_default();
+        Ok(match digits.parse::<i64>() {
+            Ok(number) => BValue::Int(number),
+            Err(_) => BValue::BigInt(digits.to_string()),
+        })
+    }
+
+    fn decode_bytes(&mut self) -> Result<&'a [u8], BencodeError> {
+        let start = self.pos;
+        let digits = self.take_until(b':', "':'")?;
+        let number = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .ok_or_else(|| self.error(start, "a string length"))?;
+        self.check_digits(start, digits)?;
+        if number > self.options.limits.max_string_len {
+            return Err(BencodeError::LimitExceeded {
+                offset: start,
+                limit: "maximum string length",
+            });
+        }
+        self.pos += 1; // ':'
+        if self.input.len() - self.pos < number {
+            return Err(self.error(self.input.len(), "string contents"));
+        }
+        let bytes = &self.input[self.pos..self.pos + number];
+        self.pos += number;
+        Ok(bytes)
+    }
+
+    fn decode_string(&mut self) -> Result<BValue, BencodeError> {
+        self.decode_bytes()
+            .map(|bytes| BValue::Bytes(bytes.to_vec()))
+    }
+
+    fn decode_list(&mut self) -> Result<BValue, BencodeError> {
+        self.enter()?;
+        self.pos += 1; // 'l'
+        let mut vec: Vec<BValue> = Vec::new();
+
+        while self.peek() != Some(b'e') {
+            vec.push(self.decode_value()?);
+        }
+        self.pos += 1; // 'e'
+        self.depth -= 1;
+        Ok(BValue::List(vec))
+    }
+
+    /// Decode a dictionary, recording in `span_of` where the value under
+    /// the given key lies.
+    fn decode_dictionary(
+        &mut self,
+        mut span_of: Option<(&[u8], &mut Option<Range<usize>>)>,
+    ) -> Result<BValue, BencodeError> {
+        self.enter()?;
+        self.pos += 1; // 'd'
+        let mut map = BTreeMap::new();
+        let mut previous: Option<&[u8]> = None;
+
+        while self.peek() != Some(b'e') {
+            if !self.peek().is_some_and(|b| b.is_ascii_digit()) {
+                self.negative_length()?;
+                return Err(self.error(self.pos, "a string key or 'e'"));
+            }
+            let key_start = self.pos;
+            let key = self.decode_bytes()?;
+            match previous.map(|previous| previous.cmp(key)) {
+                Some(Ordering::Equal) => self.violation(key_start, ViolationKind::DuplicateKey)?,
+                Some(Ordering::Greater) => self.violation(key_start, ViolationKind::UnsortedKey)?,
+                _ => {}
+            }
+            previous = Some(key);
+            let value_start = self.pos;
+            let value = self.decode_value()?;
+            if let Some((wanted, span)) = span_of.as_mut() {
+                if key == *wanted {
+                    **span = Some(value_start..self.pos);
+                }
             }
+            map.insert(key.to_vec(), value);
         }
-        panic!("Unhandled encoded value, its length : {}", en_value.len());
+        self.pos += 1; // 'e'
+        self.depth -= 1;
+        Ok(BValue::Dict(map))
     }
 }
 
@@ -309,12 +1028,12 @@ mod tests {
 
     #[test]
     fn bdecode_integer_byte() {
-        assert_eq!(b"i-5222e".bdecode().to_string(), "-5222");
+        assert_eq!(b"i-5222e".bdecode().unwrap().to_string(), "-5222");
     }
 
     #[test]
     fn bdecode_string_byte() {
-        assert_eq!(b"5:hello".bdecode().as_str().unwrap(), "hello");
+        assert_eq!(b"5:hello".bdecode().unwrap().as_str().unwrap(), "hello");
     }
 
     #[test]
@@ -322,6 +1041,7 @@ mod tests {
         assert_eq!(
             b"l5:hello3:wow7:abcdef7i77el5:helloi52eee"
                 .bdecode()
+                .unwrap()
                 .to_string(),
             "[\"hello\",\"wow\",\"abcdef7\",77,[\"hello\",52]]"
         );
@@ -330,21 +1050,538 @@ mod tests {
     #[test]
     fn bdecode_dictionary_byte() {
         assert_eq!(
-            b"d3:foo3:bar5:helloi52ee".bdecode().to_string(),
+            b"d3:foo3:bar5:helloi52ee".bdecode().unwrap().to_string(),
             "{\"foo\":\"bar\",\"hello\":52}"
         );
     }
 
+    #[test]
+    fn bdecode_binary_string_byte() {
+        let decoded = b"2:\xab\xcd".bdecode().unwrap();
+        assert_eq!(decoded, BValue::Bytes(vec![0xab, 0xcd]));
+        assert_eq!(decoded.as_str(), None);
+        assert_ne!(decoded, b"4:abcd".bdecode().unwrap());
+    }
+
+    #[test]
+    fn bvalue_json_conversion() {
+        let decoded = b"d3:foo3:bar5:helloli52eee".bdecode().unwrap();
+        let json = decoded.to_json();
+        assert_eq!(json.to_string(), "{\"foo\":\"bar\",\"hello\":[52]}");
+        assert_eq!(BValue::from_json(&json), Ok(decoded));
+        assert_eq!(
+            BValue::from_json(&serde_json::json!([true])),
+            Err(BencodeError::Unsupported { kind: "boolean" })
+        );
+    }
+
+    #[test]
+    fn bdecode_errors_byte() {
+        assert_eq!(
+            b"5hello".bdecode(),
+            Err(BencodeError::UnexpectedEof {
+                offset: 6,
+                expected: "':'"
+            })
+        );
+        assert_eq!(
+            b"i12x4e".bdecode(),
+            Err(BencodeError::Syntax {
+                offset: 1,
+                expected: "an integer",
+                excerpt: "12x4e".to_string()
+            })
+        );
+        assert_eq!(b"l5:hello".bdecode().unwrap_err().offset(), Some(8));
+        assert_eq!(b"5:hi".bdecode().unwrap_err().offset(), Some(4));
+        assert!(matches!(
+            b"di1e3:fooe".bdecode(),
+            Err(BencodeError::Syntax { offset: 1, .. })
+        ));
+        assert!(b"x".bdecode().is_err());
+        assert!(b"".bdecode().is_err());
+    }
+
+    #[test]
+    fn trailing_data() {
+        assert_eq!(b"i1exyz".bdecode().unwrap(), BValue::Int(1));
+        let (_, rest) = b"i1exyz".bdecode_each().unwrap();
+        assert_eq!(rest, b"xyz");
+        assert_eq!(
+            decode_strict(b"i1exyz"),
+            Err(BencodeError::NonCanonical {
+                offset: 3,
+                kind: ViolationKind::TrailingData
+            })
+        );
+    }
+
+    #[test]
+    fn encode_canonical() {
+        let json = serde_json::json!({"spam": ["a", -3], "cow": {"moo": 0}, "": "x"});
+        assert_eq!(
+            json.to_bencode().unwrap(),
+            b"d0:1:x3:cowd3:mooi0ee4:spaml1:ai-3eee"
+        );
+        assert_eq!(
+            BValue::Bytes(vec![0xff, 0]).to_bencode().unwrap(),
+            b"2:\xff\x00"
+        );
+        assert!(serde_json::json!(1.5).to_bencode().is_err());
+    }
+
+    /// Small xorshift generator so the round-trip property can be checked
+    /// over many values without pulling in a property-testing crate.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn bytes(&mut self) -> Vec<u8> {
+            let len = self.next() % 8;
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+
+        fn value(&mut self, depth: u32) -> BValue {
+            match self.next() % if depth == 0 { 2 } else { 4 } {
+                0 if self.next().is_multiple_of(8) => {
+                    let digits = (0..1 + self.next() % 40)
+                        .map(|_| char::from(b'1' + (self.next() % 9) as u8))
+                        .collect::<String>();
+                    let digits = format!("{}{}", ["", "-"][self.next() as usize % 2], digits);
+                    match digits.parse() {
+                        Ok(n) => BValue::Int(n),
+                        Err(_) => BValue::BigInt(digits),
+                    }
+                }
+                0 => BValue::Int(self.next() as i64 >> (self.next() % 64)),
+                1 => BValue::Bytes(self.bytes()),
+                2 => BValue::List(
+                    (0..self.next() % 4)
+                        .map(|_| self.value(depth - 1))
+                        .collect(),
+                ),
+                _ => BValue::Dict(
+                    (0..self.next() % 4)
+                        .map(|_| (self.bytes(), self.value(depth - 1)))
+                        .collect(),
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            let value = rng.value(4);
+            let encoded = value.encode();
+            assert_eq!(encoded.bdecode().unwrap(), value);
+            assert_eq!(encoded.bdecode().unwrap().encode(), encoded);
+        }
+    }
+
+    #[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+    struct Sample {
+        name: String,
+        #[serde(rename = "piece length")]
+        piece_length: u32,
+        #[serde(with = "serde_bytes")]
+        pieces: Vec<u8>,
+        comment: Option<String>,
+        private: bool,
+        kind: Kind,
+        files: Vec<Vec<String>>,
+    }
+
+    #[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+    enum Kind {
+        Single,
+        Multi { count: u8 },
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let sample = Sample {
+            name: "sample".to_string(),
+            piece_length: 16384,
+            pieces: vec![0xde, 0xad, 0xbe, 0xef],
+            comment: None,
+            private: true,
+            kind: Kind::Multi { count: 2 },
+            files: vec![vec!["a".to_string(), "b".to_string()]],
+        };
+        let encoded = to_bytes(&sample).unwrap();
+        assert_eq!(
+            encoded,
+            b"d5:filesll1:a1:bee4:kindd5:Multid5:counti2eee4:name6:sample12:piece lengthi16384e6:pieces4:\xde\xad\xbe\xef7:privatei1ee"
+        );
+        assert_eq!(from_bytes::<Sample>(&encoded).unwrap(), sample);
+
+        let encoded = b"d5:filesle4:kind6:Single4:name1:x7:comment2:hi12:piece lengthi1e6:pieces0:7:privatei0e5:extrai3ee";
+        let decoded: Sample = from_bytes(encoded).unwrap();
+        assert_eq!(decoded.kind, Kind::Single);
+        assert_eq!(decoded.comment.as_deref(), Some("hi"));
+        assert!(!decoded.private);
+    }
+
+    #[test]
+    fn bvalue_through_serde() {
+        let value = b"d1:ai-3e1:bi18446744073709551616e1:cl4:\xff\xfe\x00\x01d0:0:eee"
+            .bdecode()
+            .unwrap();
+        assert_eq!(to_value(&value).unwrap(), value);
+        assert_eq!(from_value::<BValue>(&value).unwrap(), value);
+
+        // wider than 128 bits, or with digits a number type would rewrite
+        for digits in [
+            "1".repeat(50),
+            format!("-{}", "9".repeat(45)),
+            "-09223372036854775809".into(),
+        ] {
+            let big = BValue::BigInt(digits);
+            assert_eq!(to_value(&big).unwrap(), big);
+            assert_eq!(from_value::<BValue>(&big).unwrap(), big);
+        }
+
+        #[derive(Debug, serde::Deserialize, serde::Serialize)]
+        struct Open {
+            known: i64,
+            #[serde(flatten)]
+            extra: BTreeMap<String, BValue>,
+        }
+        let input = format!("d4:hugei{}e5:knowni1e1:zli-1eee", "7".repeat(60));
+        let input = input.as_bytes();
+        let open: Open = from_bytes(input).unwrap();
+        assert_eq!(open.extra["huge"], BValue::BigInt("7".repeat(60)));
+        assert_eq!(to_bytes(&open).unwrap(), input);
+
+        // a dictionary that uses the marker as a key stays a dictionary
+        for input in [
+            &b"d5:knowni1e1:xd25:$bencode::private::BigInt5:12345ee"[..],
+            b"d5:knowni1e1:xd25:$bencode::private::BigInt1:aee",
+        ] {
+            let open: Open = from_bytes(input).unwrap();
+            assert!(matches!(open.extra["x"], BValue::Dict(_)));
+            assert_eq!(to_bytes(&open).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn serde_errors() {
+        assert!(matches!(
+            from_bytes::<Sample>(b"d4:name1:xe"),
+            Err(BencodeError::Custom(_))
+        ));
+        assert!(from_bytes::<u8>(b"i300e").is_err());
+        assert!(to_bytes(&1.5f64).is_err());
+        assert!(to_bytes(&vec![None::<u8>]).is_err());
+    }
+
+    #[test]
+    fn raw_value_span() {
+        let input = b"d4:infod6:lengthi3e4:name1:ae8:url-listl1:uee";
+        assert_eq!(
+            raw_value(input, &["info"], Limits::default()).unwrap(),
+            b"d6:lengthi3e4:name1:ae"
+        );
+        assert_eq!(
+            raw_value(input, &["info", "name"], Limits::default()).unwrap(),
+            b"1:a"
+        );
+        assert_eq!(
+            raw_value(input, &[], Limits::default()).unwrap(),
+            &input[..]
+        );
+        assert_eq!(
+            raw_value(input, &["info", "pieces"], Limits::default()),
+            Err(BencodeError::MissingKey {
+                key: "pieces".to_string()
+            })
+        );
+        assert!(raw_value(input, &["url-list", "x"], Limits::default()).is_err());
+
+        // `pieces` of a large torrent is over the untrusted string limit
+        let pieces = vec![b'a'; (1 << 20) + 20];
+        let mut input = format!("d4:infod6:pieces{}:", pieces.len()).into_bytes();
+        input.extend_from_slice(&pieces);
+        input.extend_from_slice(b"ee");
+        assert!(matches!(
+            raw_value(&input, &["info"], Limits::untrusted()),
+            Err(BencodeError::LimitExceeded { .. })
+        ));
+        let info = raw_value(&input, &["info"], Limits::torrent_file()).unwrap();
+        assert_eq!(info.len(), input.len() - 8);
+
+        let mut decoder = Decoder::new(b"i1e3:abc");
+        assert_eq!(decoder.decode_spanned().unwrap().1, 0..3);
+
+        let input = b"d4:infod6:lengthi3ee4:name1:ae";
+        let (value, span) = Decoder::new(input).decode_with_span_of("info").unwrap();
+        assert_eq!(span, Some(7..20));
+        assert_eq!(value, input.bdecode().unwrap());
+        let (_, span) = Decoder::new(b"i1e").decode_with_span_of("info").unwrap();
+        assert_eq!(span, None);
+        assert_eq!(
+            decoder.decode_spanned().unwrap(),
+            (BValue::Bytes(b"abc".to_vec()), 3..8)
+        );
+    }
+
+    #[test]
+    fn strict_rejects_non_canonical() {
+        let cases: [(&[u8], usize, ViolationKind); 8] = [
+            (b"i03e", 1, ViolationKind::LeadingZero),
+            (b"i-03e", 1, ViolationKind::LeadingZero),
+            (b"i-0e", 1, ViolationKind::NegativeZero),
+            (b"03:abc", 0, ViolationKind::LeadingZero),
+            (b"l-3:abce", 1, ViolationKind::NegativeLength),
+            (b"d1:b0:1:a0:e", 6, ViolationKind::UnsortedKey),
+            (b"d1:a0:1:a0:e", 6, ViolationKind::DuplicateKey),
+            (b"i1ei2e", 3, ViolationKind::TrailingData),
+        ];
+        for (input, offset, kind) in cases {
+            assert_eq!(
+                decode_strict(input),
+                Err(BencodeError::NonCanonical { offset, kind }),
+                "{}",
+                input.escape_ascii()
+            );
+        }
+        assert!(decode_strict(b"d1:ai0e1:bi-1e1:cl0:ee").is_ok());
+        assert!(b"i03e".bdecode().is_ok());
+    }
+
+    #[test]
+    fn lint_reports_every_violation() {
+        let report = lint(b"d1:bi03e1:a0:1:a0:ei1e", Limits::default());
+        assert_eq!(
+            report.violations,
+            vec![
+                Violation {
+                    offset: 5,
+                    kind: ViolationKind::LeadingZero
+                },
+                Violation {
+                    offset: 8,
+                    kind: ViolationKind::UnsortedKey
+                },
+                Violation {
+                    offset: 13,
+                    kind: ViolationKind::DuplicateKey
+                },
+                Violation {
+                    offset: 19,
+                    kind: ViolationKind::TrailingData
+                },
+            ]
+        );
+        assert_eq!(report.error, None);
+        assert!(lint(b"d1:ai1ee", Limits::default()).is_clean());
+        assert!(lint(b"li01e", Limits::default()).error.is_some());
+    }
+
+    fn decode_limited(input: &[u8], limits: Limits) -> Result<BValue, BencodeError> {
+        let options = DecodeOptions {
+            limits,
+            ..DecodeOptions::default()
+        };
+        Decoder::with_options(input, options).decode_value()
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let deep = [vec![b'l'; 33], vec![b'e'; 33]].concat();
+        assert_eq!(
+            deep.bdecode(),
+            Err(BencodeError::LimitExceeded {
+                offset: 32,
+                limit: "maximum nesting depth"
+            })
+        );
+        assert!(decode_limited(&deep, Limits::torrent_file()).is_ok());
+
+        let limits = Limits {
+            max_depth: 4,
+            max_string_len: 3,
+            max_items: 3,
+            max_input_len: 16,
+        };
+        assert!(decode_limited(b"3:abc", limits).is_ok());
+        assert_eq!(
+            decode_limited(b"l3:abc4:abcde", limits),
+            Err(BencodeError::LimitExceeded {
+                offset: 6,
+                limit: "maximum string length"
+            })
+        );
+        assert_eq!(
+            decode_limited(b"li1ei2ei3ee", limits),
+            Err(BencodeError::LimitExceeded {
+                offset: 7,
+                limit: "maximum item count"
+            })
+        );
+        assert!(matches!(
+            decode_limited(b"17:aaaaaaaaaaaaaaaaa", limits),
+            Err(BencodeError::LimitExceeded { offset: 0, .. })
+        ));
+        // a length prefix far beyond the input is rejected before reading it
+        assert!(matches!(
+            b"4294967296:".bdecode(),
+            Err(BencodeError::LimitExceeded { offset: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn stream_decoder_byte_by_byte() {
+        let input = b"d3:fooli1e3:bare1:xi-12ee";
+        let mut decoder = StreamDecoder::new();
+        for &b in &input[..input.len() - 1] {
+            assert_eq!(decoder.push(&[b]), Ok(Progress::NeedMoreData));
+        }
+        assert_eq!(
+            decoder.push(&input[input.len() - 1..]),
+            Ok(Progress::Complete {
+                value: input.bdecode().unwrap(),
+                consumed: input.len()
+            })
+        );
+        assert!(decoder.buffered().is_empty());
+        assert!(decoder.push(b"x").is_err());
+    }
+
+    #[test]
+    fn stream_decoder_resumes_and_fails_early() {
+        // scanning picks up where the previous push stopped
+        let mut decoder = StreamDecoder::new();
+        let input = format!("l{}e", "3:abc".repeat(100));
+        for chunk in input.as_bytes()[..input.len() - 1].chunks(7) {
+            assert_eq!(decoder.push(chunk), Ok(Progress::NeedMoreData));
+            assert_eq!(decoder.scan.pos, decoder.buffered().len());
+        }
+        assert!(matches!(
+            decoder.push(b"e"),
+            Ok(Progress::Complete { consumed, .. }) if consumed == input.len()
+        ));
+
+        let mut decoder = StreamDecoder::new();
+        assert_eq!(decoder.push(b"5"), Ok(Progress::NeedMoreData));
+        assert!(matches!(
+            decoder.push(b"hello"),
+            Err(BencodeError::Syntax { offset: 1, .. })
+        ));
+        let mut decoder = StreamDecoder::new();
+        assert!(matches!(
+            decoder.push(b"di1e"),
+            Err(BencodeError::Syntax { offset: 1, .. })
+        ));
+
+        let options = DecodeOptions {
+            limits: Limits {
+                max_string_len: 4,
+                ..Limits::untrusted()
+            },
+            ..DecodeOptions::default()
+        };
+        let mut decoder = StreamDecoder::with_options(options);
+        assert!(matches!(
+            decoder.push(b"l10"),
+            Err(BencodeError::LimitExceeded {
+                limit: "maximum string length",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn stream_decoder_trailing_payload() {
+        // ut_metadata data message: bencoded header followed by raw bytes
+        let mut decoder = StreamDecoder::new();
+        assert_eq!(
+            decoder.push(b"d8:msg_typei1e5:pie"),
+            Ok(Progress::NeedMoreData)
+        );
+        let Ok(Progress::Complete { value, consumed }) = decoder.push(b"cei0eeRAW\x00DATA") else {
+            panic!("header should be complete");
+        };
+        assert_eq!(value.get("piece"), Some(&BValue::Int(0)));
+        assert_eq!(consumed, 25);
+        assert_eq!(decoder.take_buffered(), b"RAW\x00DATA");
+
+        assert_eq!(
+            decoder.push(b"i1ei2"),
+            Ok(Progress::Complete {
+                value: BValue::Int(1),
+                consumed: 3
+            })
+        );
+        assert_eq!(
+            decoder.push(b"e"),
+            Ok(Progress::Complete {
+                value: BValue::Int(2),
+                consumed: 3
+            })
+        );
+        assert_eq!(decoder.poll(), Ok(Progress::NeedMoreData));
+    }
+
+    #[test]
+    fn bdecode_keeps_peers_generic() {
+        assert_eq!(
+            b"d5:peers6:\x7f\x00\x00\x01\x1a\xe1e".bdecode().unwrap(),
+            BValue::Dict(BTreeMap::from([(
+                b"peers".to_vec(),
+                BValue::Bytes(b"\x7f\x00\x00\x01\x1a\xe1".to_vec())
+            )]))
+        );
+    }
+
+    #[test]
+    fn big_integers() {
+        let input = b"li9223372036854775807ei9223372036854775808ei-170141183460469231731687303715884105729ee";
+        let decoded = input.bdecode().unwrap();
+        assert_eq!(
+            decoded,
+            BValue::List(vec![
+                BValue::Int(i64::MAX),
+                BValue::BigInt("9223372036854775808".to_string()),
+                BValue::BigInt("-170141183460469231731687303715884105729".to_string()),
+            ])
+        );
+        assert_eq!(decoded.encode(), input);
+        assert_eq!(
+            decoded.to_string(),
+            "[9223372036854775807,9223372036854775808,\"-170141183460469231731687303715884105729\"]"
+        );
+        assert!(decode_strict(b"i-09223372036854775809e").is_err());
+
+        assert_eq!(from_bytes::<u64>(b"i18446744073709551615e"), Ok(u64::MAX));
+        assert_eq!(
+            from_bytes::<i128>(b"i-9223372036854775809e"),
+            Ok(-9223372036854775809)
+        );
+        assert!(from_bytes::<u64>(b"i18446744073709551616e").is_err());
+        assert_eq!(to_bytes(&u64::MAX).unwrap(), b"i18446744073709551615e");
+        assert!(b"i1-2e".bdecode().is_err());
+        assert!(b"i--1e".bdecode().is_err());
+    }
+
     /////////////////////////////////////////
 
     #[test]
     fn bdecode_string() {
-        assert_eq!("i52e".bdecode().to_string(), "52");
+        assert_eq!("i52e".bdecode().unwrap().to_string(), "52");
     }
 
     #[test]
     fn bdecode_integer() {
-        assert_eq!("5:hello".bdecode().as_str().unwrap(), "hello");
+        assert_eq!("5:hello".bdecode().unwrap().as_str().unwrap(), "hello");
     }
 
     #[test]
@@ -352,6 +1589,7 @@ mod tests {
         assert_eq!(
             "l5:hello3:wow7:abcdef7i77el5:helloi52eee"
                 .bdecode()
+                .unwrap()
                 .to_string(),
             "[\"hello\",\"wow\",\"abcdef7\",77,[\"hello\",52]]"
         );
@@ -360,8 +1598,59 @@ mod tests {
     #[test]
     fn bdecode_dictionary() {
         assert_eq!(
-            "d3:foo3:bar5:helloi52ee".bdecode().to_string(),
+            "d3:foo3:bar5:helloi52ee".bdecode().unwrap().to_string(),
             "{\"foo\":\"bar\",\"hello\":52}"
         );
     }
+
+    #[test]
+    fn bdecode_non_ascii() {
+        assert_eq!("5:héé".bdecode().unwrap().as_str(), Some("héé"));
+        assert_eq!(
+            "d5:café6:crèmee".bdecode().unwrap().to_string(),
+            "{\"café\":\"crème\"}"
+        );
+        // the length counts bytes, so this stops in the middle of the last 'é'
+        assert_eq!(
+            "4:héé".bdecode().unwrap(),
+            BValue::Bytes(b"h\xc3\xa9\xc3".to_vec())
+        );
+        assert!("4:héé".bdecode_each().is_err());
+        assert_eq!("3:hé!".bdecode_each().unwrap().1, "!");
+    }
+
+    #[test]
+    fn bdecode_str_matches_bytes() {
+        for input in [
+            "i-5e",
+            "5:héé",
+            "4:héé",
+            "l1:é2:éi0ee",
+            "d2:éé1:xe",
+            "3:é",
+            "i1",
+        ] {
+            let from_str = input.bdecode();
+            assert_eq!(from_str, input.as_bytes().bdecode(), "{}", input);
+            if let Ok(value) = from_str {
+                assert_eq!(
+                    value.to_string(),
+                    input.as_bytes().bdecode().unwrap().to_string()
+                );
+            }
+        }
+        assert_eq!(b"3:\xff\x00a".bdecode().unwrap().to_string(), "\"ff0061\"");
+    }
+
+    #[test]
+    fn bdecode_errors() {
+        assert_eq!("l5:hello".bdecode().unwrap_err().offset(), Some(8));
+        assert_eq!(
+            "99999999999999999999:a".bdecode().unwrap_err().offset(),
+            Some(0)
+        );
+        assert_eq!("9:a".bdecode().unwrap_err().offset(), Some(3));
+        assert!("d3:fooe".bdecode().is_err());
+        assert!("i-e".bdecode().is_err());
+    }
 }
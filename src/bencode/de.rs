@@ -0,0 +1,352 @@
+//! serde `Deserializer` over decoded [`BValue`]s
+
+use super::{is_integer_digits, BValue, Bencode, BencodeError};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::{forward_to_deserialize_any, Deserialize};
+use std::collections::btree_map;
+use std::fmt;
+
+impl de::Error for BencodeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        BencodeError::Custom(msg.to_string())
+    }
+}
+
+/// Deserialize a `T` from bencoded bytes.
+pub fn from_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BencodeError> {
+    let value = bytes.bdecode()?;
+    from_value(&value)
+}
+
+/// Deserialize a `T` from an already decoded value, borrowing strings and
+/// byte strings from it where `T` allows.
+pub fn from_value<'de, T: Deserialize<'de>>(value: &'de BValue) -> Result<T, BencodeError> {
+    T::deserialize(value)
+}
+
+/// Any bencoded value, so that structs can keep keys they do not model
+/// (e.g. with `#[serde(flatten)]`). Integers of any size survive the trip.
+impl<'de> Deserialize<'de> for BValue {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<BValue, D::Error> {
+        deserializer.deserialize_any(BValueVisitor)
+    }
+}
+
+struct BValueVisitor;
+
+impl<'de> Visitor<'de> for BValueVisitor {
+    type Value = BValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a bencode value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<BValue, E> {
+        Ok(BValue::Int(v.into()))
+    }
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<BValue, E> {
+        Ok(BValue::Int(v))
+    }
+    fn visit_i128<E: de::Error>(self, v: i128) -> Result<BValue, E> {
+        Ok(i64::try_from(v).map_or_else(|_| BValue::BigInt(v.to_string()), BValue::Int))
+    }
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<BValue, E> {
+        Ok(i64::try_from(v).map_or_else(|_| BValue::BigInt(v.to_string()), BValue::Int))
+    }
+    fn visit_u128<E: de::Error>(self, v: u128) -> Result<BValue, E> {
+        Ok(i64::try_from(v).map_or_else(|_| BValue::BigInt(v.to_string()), BValue::Int))
+    }
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<BValue, E> {
+        Ok(BValue::Bytes(v.as_bytes().to_vec()))
+    }
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<BValue, E> {
+        Ok(BValue::Bytes(v.to_vec()))
+    }
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<BValue, A::Error> {
+        let mut list = Vec::new();
+        while let Some(value) = seq.next_element()? {
+            list.push(value);
+        }
+        Ok(BValue::List(list))
+    }
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<BValue, A::Error> {
+        let mut dict = std::collections::BTreeMap::new();
+        while let Some(key) = map.next_key::<serde_bytes::ByteBuf>()? {
+            dict.insert(key.into_vec(), map.next_value()?);
+        }
+        Ok(BValue::Dict(dict))
+    }
+    /// Bencode has no newtypes: only a big integer is presented as one.
+    fn visit_newtype_struct<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<BValue, D::Error> {
+        let digits = String::deserialize(deserializer)?;
+        if !is_integer_digits(digits.as_bytes()) {
+            return Err(de::Error::custom("malformed big integer"));
+        }
+        Ok(BValue::BigInt(digits))
+    }
+}
+
+fn visit_bytes<'de, V: Visitor<'de>>(
+    bytes: &'de [u8],
+    visitor: V,
+) -> Result<V::Value, BencodeError> {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => visitor.visit_borrowed_str(s),
+        Err(_) => visitor.visit_borrowed_bytes(bytes),
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &'de BValue {
+    type Error = BencodeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match self {
+            BValue::Int(n) => visitor.visit_i64(*n),
+            BValue::BigInt(digits) => {
+                let canonical = |n: &dyn ToString| n.to_string() == *digits;
+                match (
+                    digits.parse::<u64>(),
+                    digits.parse::<i128>(),
+                    digits.parse::<u128>(),
+                ) {
+                    (Ok(n), _, _) if canonical(&n) => visitor.visit_u64(n),
+                    (_, Ok(n), _) if canonical(&n) => visitor.visit_i128(n),
+                    (_, _, Ok(n)) if canonical(&n) => visitor.visit_u128(n),
+                    // anything else reaches `BValue` digit for digit as a
+                    // newtype, which no decoded dictionary can pass for
+                    _ => visitor.visit_newtype_struct(de::value::BorrowedStrDeserializer::<
+                        BencodeError,
+                    >::new(digits)),
+                }
+            }
+            BValue::Bytes(bytes) => visit_bytes(bytes, visitor),
+            BValue::List(vec) => visitor.visit_seq(SeqAccess { iter: vec.iter() }),
+            BValue::Dict(map) => visitor.visit_map(MapAccess {
+                iter: map.iter(),
+                value: None,
+            }),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match self {
+            BValue::Int(0) => visitor.visit_bool(false),
+            BValue::Int(1) => visitor.visit_bool(true),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match self {
+            BValue::Bytes(bytes) => visitor.visit_borrowed_bytes(bytes),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        // bencode has no null; a present value is always `Some`
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        match self {
+            BValue::Bytes(_) => visitor.visit_enum(EnumAccess {
+                variant: self,
+                value: None,
+            }),
+            BValue::Dict(map) if map.len() == 1 => {
+                let (variant, value) = map.iter().next().unwrap();
+                visitor.visit_enum(EnumAccess {
+                    variant: KeyDeserializer(variant),
+                    value: Some(value),
+                })
+            }
+            _ => Err(de::Error::custom(
+                "expected a string or a single-key dictionary for an enum",
+            )),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Deserializer for dictionary keys, which are always byte strings.
+struct KeyDeserializer<'de>(&'de [u8]);
+
+impl<'de> de::Deserializer<'de> for KeyDeserializer<'de> {
+    type Error = BencodeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        visit_bytes(self.0, visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        visitor.visit_borrowed_bytes(self.0)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        visitor.visit_borrowed_bytes(self.0)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        option unit unit_struct newtype_struct seq tuple tuple_struct map struct
+        enum identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, BencodeError> for KeyDeserializer<'de> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+struct SeqAccess<'de> {
+    iter: std::slice::Iter<'de, BValue>,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqAccess<'de> {
+    type Error = BencodeError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, BencodeError> {
+        self.iter
+            .next()
+            .map(|value| seed.deserialize(value))
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct MapAccess<'de> {
+    iter: btree_map::Iter<'de, Vec<u8>, BValue>,
+    value: Option<&'de BValue>,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess<'de> {
+    type Error = BencodeError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, BencodeError> {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(KeyDeserializer(key)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, BencodeError> {
+        let value = self
+            .value
+            .take()
+            .ok_or_else(|| de::Error::custom("value requested before key"))?;
+        seed.deserialize(value)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct EnumAccess<'de, D> {
+    variant: D,
+    value: Option<&'de BValue>,
+}
+
+impl<'de, D> de::EnumAccess<'de> for EnumAccess<'de, D>
+where
+    D: de::Deserializer<'de, Error = BencodeError>,
+{
+    type Error = BencodeError;
+    type Variant = VariantAccess<'de>;
+
+    fn variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<(T::Value, VariantAccess<'de>), BencodeError> {
+        let variant = seed.deserialize(self.variant)?;
+        Ok((variant, VariantAccess { value: self.value }))
+    }
+}
+
+struct VariantAccess<'de> {
+    value: Option<&'de BValue>,
+}
+
+impl<'de> VariantAccess<'de> {
+    fn value(self) -> Result<&'de BValue, BencodeError> {
+        self.value
+            .ok_or_else(|| de::Error::custom("expected an enum variant with a value"))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for VariantAccess<'de> {
+    type Error = BencodeError;
+
+    fn unit_variant(self) -> Result<(), BencodeError> {
+        match self.value {
+            None => Ok(()),
+            Some(_) => Err(de::Error::custom("expected a unit variant")),
+        }
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, BencodeError> {
+        seed.deserialize(self.value()?)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        de::Deserializer::deserialize_seq(self.value()?, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        de::Deserializer::deserialize_map(self.value()?, visitor)
+    }
+}
@@ -0,0 +1,218 @@
+//! Path queries over decoded [`BValue`]s
+//!
+//! A path is a `.`-separated list of dictionary keys, each optionally
+//! followed by `[n]` list indices, e.g. `info.files[2].path`. Keys cannot
+//! themselves contain `.`, `[` or `]`.
+
+use super::BValue;
+use std::collections::BTreeMap;
+use std::fmt;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PathError {
+    #[error("invalid path `{0}`")]
+    Syntax(String),
+    #[error("{parent} has no key `{key}`")]
+    MissingKey { parent: Location, key: String },
+    #[error("{parent} has no index {index} (length {len})")]
+    MissingIndex {
+        parent: Location,
+        index: usize,
+        len: usize,
+    },
+    #[error("{location} is not {expected}")]
+    WrongType {
+        location: Location,
+        expected: &'static str,
+    },
+}
+
+/// The part of a path walked so far, used in error messages.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location(String);
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "the top-level value")
+        } else {
+            write!(f, "`{}`", self.0)
+        }
+    }
+}
+
+enum Segment<'p> {
+    Key(&'p str),
+    Index(usize),
+}
+
+fn parse(path: &str) -> Result<Vec<Segment<'_>>, PathError> {
+    let syntax = || PathError::Syntax(path.to_string());
+    let mut segments = Vec::new();
+    if path.is_empty() {
+        return Ok(segments);
+    }
+
+    for (n, part) in path.split('.').enumerate() {
+        let (key, mut rest) = part.split_at(part.find('[').unwrap_or(part.len()));
+        if key.contains(']') {
+            return Err(syntax());
+        } else if !key.is_empty() {
+            segments.push(Segment::Key(key));
+        } else if rest.is_empty() || n > 0 {
+            return Err(syntax());
+        }
+        while !rest.is_empty() {
+            let close = rest.find(']').ok_or_else(syntax)?;
+            let index = rest
+                .strip_prefix('[')
+                .and_then(|r| r[..close - 1].parse::<usize>().ok())
+                .ok_or_else(syntax)?;
+            segments.push(Segment::Index(index));
+            rest = &rest[close + 1..];
+        }
+    }
+    Ok(segments)
+}
+
+impl BValue {
+    /// Follow `path` from this value.
+    pub fn get_path(&self, path: &str) -> Result<&BValue, PathError> {
+        self.walk(path).map(|(value, _)| value)
+    }
+
+    fn walk(&self, path: &str) -> Result<(&BValue, Location), PathError> {
+        let mut value = self;
+        let mut location = Location::default();
+
+        for segment in parse(path)? {
+            value = match segment {
+                Segment::Key(key) => {
+                    let map = value.as_dict().ok_or_else(|| PathError::WrongType {
+                        location: location.clone(),
+                        expected: "a dictionary",
+                    })?;
+                    let next = map
+                        .get(key.as_bytes())
+                        .ok_or_else(|| PathError::MissingKey {
+                            parent: location.clone(),
+                            key: key.to_string(),
+                        })?;
+                    if !location.0.is_empty() {
+                        location.0.push('.');
+                    }
+                    location.0.push_str(key);
+                    next
+                }
+                Segment::Index(index) => {
+                    let list = value.as_list().ok_or_else(|| PathError::WrongType {
+                        location: location.clone(),
+                        expected: "a list",
+                    })?;
+                    let next = list.get(index).ok_or_else(|| PathError::MissingIndex {
+                        parent: location.clone(),
+                        index,
+                        len: list.len(),
+                    })?;
+                    location.0.push_str(&format!("[{}]", index));
+                    next
+                }
+            };
+        }
+        Ok((value, location))
+    }
+
+    fn get_typed<'v, T>(
+        &'v self,
+        path: &str,
+        expected: &'static str,
+        convert: impl FnOnce(&'v BValue) -> Option<T>,
+    ) -> Result<T, PathError> {
+        let (value, location) = self.walk(path)?;
+        convert(value).ok_or(PathError::WrongType { location, expected })
+    }
+
+    pub fn get_int(&self, path: &str) -> Result<i64, PathError> {
+        self.get_typed(path, "an integer", BValue::as_int)
+    }
+
+    pub fn get_bytes(&self, path: &str) -> Result<&[u8], PathError> {
+        self.get_typed(path, "a byte string", BValue::as_bytes)
+    }
+
+    pub fn get_str(&self, path: &str) -> Result<&str, PathError> {
+        self.get_typed(path, "a UTF-8 string", BValue::as_str)
+    }
+
+    pub fn get_list(&self, path: &str) -> Result<&[BValue], PathError> {
+        self.get_typed(path, "a list", BValue::as_list)
+    }
+
+    pub fn get_dict(&self, path: &str) -> Result<&BTreeMap<Vec<u8>, BValue>, PathError> {
+        self.get_typed(path, "a dictionary", BValue::as_dict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::Bencode;
+
+    fn sample() -> BValue {
+        b"d4:infod5:filesld6:lengthi1e4:pathl1:a1:beed6:lengthi2e4:pathl1:ceee12:piece lengthi16384eee"
+            .bdecode()
+            .unwrap()
+    }
+
+    #[test]
+    fn get_path_finds_values() {
+        let value = sample();
+        assert_eq!(value.get_int("info.piece length"), Ok(16384));
+        assert_eq!(value.get_str("info.files[0].path[1]"), Ok("b"));
+        assert_eq!(value.get_list("info.files[1].path").unwrap().len(), 1);
+        assert_eq!(value.get_path(""), Ok(&value));
+        assert_eq!(
+            value.get_list("info.files").unwrap()[0].get_str("path[0]"),
+            Ok("a")
+        );
+    }
+
+    #[test]
+    fn get_path_names_the_failing_segment() {
+        let value = sample();
+        assert_eq!(
+            value.get_int("info.lenght").unwrap_err().to_string(),
+            "`info` has no key `lenght`"
+        );
+        assert_eq!(
+            value.get_path("info.files[2]").unwrap_err().to_string(),
+            "`info.files` has no index 2 (length 2)"
+        );
+        assert_eq!(
+            value.get_int("info.files[0].path").unwrap_err().to_string(),
+            "`info.files[0].path` is not an integer"
+        );
+        assert_eq!(
+            value.get_path("announce").unwrap_err().to_string(),
+            "the top-level value has no key `announce`"
+        );
+        assert_eq!(
+            value.get_path("info[0]").unwrap_err().to_string(),
+            "`info` is not a list"
+        );
+        for bad in [
+            "info..files",
+            "info.files[",
+            "info.files[x]",
+            "info.[0]",
+            "a]",
+        ] {
+            assert!(
+                matches!(value.get_path(bad), Err(PathError::Syntax(_))),
+                "{}",
+                bad
+            );
+        }
+    }
+}
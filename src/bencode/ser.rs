@@ -0,0 +1,397 @@
+//! serde `Serializer` producing [`BValue`]s
+
+use super::{is_integer_digits, BValue, BencodeError, BIG_INT_MARKER};
+use serde::ser::{self, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+impl ser::Error for BencodeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        BencodeError::Custom(msg.to_string())
+    }
+}
+
+/// Serialize a `T` into canonical bencoded bytes.
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, BencodeError> {
+    to_value(value).map(|value| value.encode())
+}
+
+/// Serialize a `T` into a [`BValue`].
+///
+/// Struct fields and map entries holding `None` are left out, since bencode
+/// has no null; a `None` anywhere else is an error.
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<BValue, BencodeError> {
+    value
+        .serialize(Serializer)?
+        .ok_or(BencodeError::Unsupported { kind: "none" })
+}
+
+/// Serializes into `Option<BValue>`, where `None` marks an absent value.
+struct Serializer;
+
+type Output = Option<BValue>;
+
+fn some(value: &(impl Serialize + ?Sized)) -> Result<BValue, BencodeError> {
+    to_value(value)
+}
+
+fn int<T: TryInto<i64> + ToString + Copy>(n: T) -> Result<Output, BencodeError> {
+    Ok(Some(match n.try_into() {
+        Ok(n) => BValue::Int(n),
+        Err(_) => BValue::BigInt(n.to_string()),
+    }))
+}
+
+impl Serialize for BValue {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use ser::{SerializeMap, SerializeSeq};
+        match self {
+            BValue::Int(n) => serializer.serialize_i64(*n),
+            BValue::BigInt(digits) => match (digits.parse::<i128>(), digits.parse::<u128>()) {
+                (Ok(n), _) if n.to_string() == *digits => serializer.serialize_i128(n),
+                (_, Ok(n)) if n.to_string() == *digits => serializer.serialize_u128(n),
+                // wider than 128 bits, or not canonical: keep the digits
+                _ => serializer.serialize_newtype_struct(BIG_INT_MARKER, digits),
+            },
+            BValue::Bytes(bytes) => serializer.serialize_bytes(bytes),
+            BValue::List(list) => {
+                let mut seq = serializer.serialize_seq(Some(list.len()))?;
+                for value in list {
+                    seq.serialize_element(value)?;
+                }
+                seq.end()
+            }
+            BValue::Dict(dict) => {
+                let mut map = serializer.serialize_map(Some(dict.len()))?;
+                for (key, value) in dict {
+                    map.serialize_entry(serde_bytes::Bytes::new(key), value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+fn wrap_variant(name: &str, value: BValue) -> Output {
+    Some(BValue::Dict(BTreeMap::from([(
+        name.as_bytes().to_vec(),
+        value,
+    )])))
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Output;
+    type Error = BencodeError;
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeList;
+    type SerializeMap = SerializeDict;
+    type SerializeStruct = SerializeDict;
+    type SerializeStructVariant = SerializeDict;
+
+    fn serialize_bool(self, v: bool) -> Result<Output, BencodeError> {
+        int(v as i64)
+    }
+    fn serialize_i8(self, v: i8) -> Result<Output, BencodeError> {
+        int(v)
+    }
+    fn serialize_i16(self, v: i16) -> Result<Output, BencodeError> {
+        int(v)
+    }
+    fn serialize_i32(self, v: i32) -> Result<Output, BencodeError> {
+        int(v)
+    }
+    fn serialize_i64(self, v: i64) -> Result<Output, BencodeError> {
+        int(v)
+    }
+    fn serialize_i128(self, v: i128) -> Result<Output, BencodeError> {
+        int(v)
+    }
+    fn serialize_u8(self, v: u8) -> Result<Output, BencodeError> {
+        int(v)
+    }
+    fn serialize_u16(self, v: u16) -> Result<Output, BencodeError> {
+        int(v)
+    }
+    fn serialize_u32(self, v: u32) -> Result<Output, BencodeError> {
+        int(v)
+    }
+    fn serialize_u64(self, v: u64) -> Result<Output, BencodeError> {
+        int(v)
+    }
+    fn serialize_u128(self, v: u128) -> Result<Output, BencodeError> {
+        int(v)
+    }
+    fn serialize_f32(self, _v: f32) -> Result<Output, BencodeError> {
+        Err(BencodeError::Unsupported { kind: "float" })
+    }
+    fn serialize_f64(self, _v: f64) -> Result<Output, BencodeError> {
+        Err(BencodeError::Unsupported { kind: "float" })
+    }
+    fn serialize_char(self, v: char) -> Result<Output, BencodeError> {
+        self.serialize_str(v.encode_utf8(&mut [0u8; 4]))
+    }
+    fn serialize_str(self, v: &str) -> Result<Output, BencodeError> {
+        Ok(Some(BValue::Bytes(v.as_bytes().to_vec())))
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<Output, BencodeError> {
+        Ok(Some(BValue::Bytes(v.to_vec())))
+    }
+    fn serialize_none(self) -> Result<Output, BencodeError> {
+        Ok(None)
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Output, BencodeError> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Output, BencodeError> {
+        Err(BencodeError::Unsupported { kind: "unit" })
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Output, BencodeError> {
+        self.serialize_unit()
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Output, BencodeError> {
+        self.serialize_str(variant)
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Output, BencodeError> {
+        if name != BIG_INT_MARKER {
+            return value.serialize(self);
+        }
+        match some(value)? {
+            BValue::Bytes(digits) if is_integer_digits(&digits) => {
+                // checked to be ASCII above
+                Ok(Some(BValue::BigInt(
+                    String::from_utf8(digits).unwrap_or_default(),
+                )))
+            }
+            _ => Err(ser::Error::custom("malformed big integer")),
+        }
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Output, BencodeError> {
+        Ok(wrap_variant(variant, some(value)?))
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeList, BencodeError> {
+        Ok(SerializeList {
+            vec: Vec::with_capacity(len.unwrap_or(0)),
+            variant: None,
+        })
+    }
+    fn serialize_tuple(self, len: usize) -> Result<SerializeList, BencodeError> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, BencodeError> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, BencodeError> {
+        Ok(SerializeList {
+            vec: Vec::with_capacity(len),
+            variant: Some(variant),
+        })
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeDict, BencodeError> {
+        Ok(SerializeDict {
+            map: BTreeMap::new(),
+            key: None,
+            variant: None,
+        })
+    }
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeDict, BencodeError> {
+        self.serialize_map(Some(len))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeDict, BencodeError> {
+        Ok(SerializeDict {
+            map: BTreeMap::new(),
+            key: None,
+            variant: Some(variant),
+        })
+    }
+}
+
+struct SerializeList {
+    vec: Vec<BValue>,
+    variant: Option<&'static str>,
+}
+
+impl SerializeList {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.vec.push(some(value)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Output, BencodeError> {
+        let list = BValue::List(self.vec);
+        Ok(match self.variant {
+            Some(name) => wrap_variant(name, list),
+            None => Some(list),
+        })
+    }
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = Output;
+    type Error = BencodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Output, BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = Output;
+    type Error = BencodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Output, BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = Output;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Output, BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeList {
+    type Ok = Output;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Output, BencodeError> {
+        self.finish()
+    }
+}
+
+struct SerializeDict {
+    map: BTreeMap<Vec<u8>, BValue>,
+    key: Option<Vec<u8>>,
+    variant: Option<&'static str>,
+}
+
+impl SerializeDict {
+    fn insert<T: Serialize + ?Sized>(
+        &mut self,
+        key: Vec<u8>,
+        value: &T,
+    ) -> Result<(), BencodeError> {
+        if let Some(value) = value.serialize(Serializer)? {
+            self.map.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Output, BencodeError> {
+        let dict = BValue::Dict(self.map);
+        Ok(match self.variant {
+            Some(name) => wrap_variant(name, dict),
+            None => Some(dict),
+        })
+    }
+}
+
+impl ser::SerializeMap for SerializeDict {
+    type Ok = Output;
+    type Error = BencodeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), BencodeError> {
+        match some(key)? {
+            BValue::Bytes(key) => {
+                self.key = Some(key);
+                Ok(())
+            }
+            _ => Err(BencodeError::Unsupported {
+                kind: "non-string dictionary key",
+            }),
+        }
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| <BencodeError as ser::Error>::custom("value serialized before key"))?;
+        self.insert(key, value)
+    }
+    fn end(self) -> Result<Output, BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeDict {
+    type Ok = Output;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), BencodeError> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+    fn end(self) -> Result<Output, BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeDict {
+    type Ok = Output;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), BencodeError> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+    fn end(self) -> Result<Output, BencodeError> {
+        self.finish()
+    }
+}
@@ -0,0 +1,408 @@
+//! # Create
+//!
+//! build `.torrent` files from a file or directory on disk
+//!
+
+use crate::bencode;
+use crate::merkle::{self, BLOCK_SIZE};
+use crate::sha256::Sha256;
+use crate::torrent::{FileEntry, Info, MetaInfo, OneOrMany};
+use crate::v2::{self, V2File};
+use anyhow::{bail, Context};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// What goes into a new torrent besides the data itself.
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    /// Bytes per piece; picked from the total size by
+    /// [`auto_piece_length`] when `None`.
+    pub piece_length: Option<usize>,
+    /// Tracker tiers. The first URL is also written as `announce`, and
+    /// `announce-list` is only written when there is more than one URL.
+    pub trackers: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub creation_date: Option<i64>,
+    pub private: bool,
+    /// Written as `info.source`, which changes the info hash.
+    pub source: Option<String>,
+    pub web_seeds: Vec<String>,
+    /// Also write the v2 `file tree` and `piece layers` (BEP 52), padding
+    /// the v1 files so both versions share the same pieces.
+    pub hybrid: bool,
+}
+
+/// Smallest and largest piece length picked automatically.
+const MIN_PIECE_LENGTH: usize = 1 << 14;
+const MAX_PIECE_LENGTH: usize = 1 << 24;
+/// Roughly how many pieces an automatic piece length aims for.
+const TARGET_PIECES: u64 = 1500;
+
+/// A power-of-two piece length giving about [`TARGET_PIECES`] pieces,
+/// between 16 KiB and 16 MiB.
+pub fn auto_piece_length(total_length: u64) -> usize {
+    let mut piece_length = MIN_PIECE_LENGTH;
+    while piece_length < MAX_PIECE_LENGTH && total_length / piece_length as u64 > TARGET_PIECES {
+        piece_length *= 2;
+    }
+    piece_length
+}
+
+/// Build the metainfo for the file or directory at `path`. Directory
+/// contents are hashed in sorted path order.
+pub fn create_metainfo(path: &Path, options: &CreateOptions) -> anyhow::Result<MetaInfo> {
+    let name = path
+        .canonicalize()
+        .with_context(|| format!("could not read {}", path.display()))?
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("the path has no UTF-8 file name")?
+        .to_string();
+
+    let metadata = fs::metadata(path)?;
+    // path components below the torrent, where the file is, and its length
+    let files: Vec<(Vec<String>, PathBuf, usize)> = if metadata.is_dir() {
+        let mut files = Vec::new();
+        collect_files(path, &mut Vec::new(), &mut files)?;
+        if files.is_empty() {
+            bail!("{} contains no files", path.display());
+        }
+        files
+            .into_iter()
+            .map(|(components, length)| {
+                let on_disk = path.join(components.iter().collect::<PathBuf>());
+                (components, on_disk, length)
+            })
+            .collect()
+    } else {
+        vec![(
+            vec![name.clone()],
+            path.to_path_buf(),
+            metadata.len() as usize,
+        )]
+    };
+
+    let total_length: u64 = files.iter().map(|(_, _, length)| *length as u64).sum();
+    let piece_length = options
+        .piece_length
+        .unwrap_or_else(|| auto_piece_length(total_length));
+    if piece_length == 0 {
+        bail!("piece length must not be zero");
+    }
+    if options.hybrid {
+        v2::check_piece_length(piece_length)?;
+    }
+
+    // v1 data in piece order; hybrids pad every file but the last to a piece
+    // boundary so that v1 and v2 pieces line up (BEP 47)
+    let mut parts = Vec::new();
+    let mut entries = Vec::new();
+    for (i, (components, on_disk, length)) in files.iter().enumerate() {
+        parts.push((Some(on_disk.clone()), *length));
+        entries.push(FileEntry {
+            length: *length,
+            path: components
+                .iter()
+                .map(|c| ByteBuf::from(c.as_bytes()))
+                .collect(),
+            md5sum: None,
+            attr: None,
+        });
+        let padding = (piece_length - length % piece_length) % piece_length;
+        if options.hybrid && padding > 0 && i + 1 < files.len() {
+            parts.push((None, padding));
+            entries.push(FileEntry {
+                length: padding,
+                path: vec![ByteBuf::from(".pad"), ByteBuf::from(padding.to_string())],
+                md5sum: None,
+                attr: Some("p".to_string()),
+            });
+        }
+    }
+
+    let (file_tree, piece_layers) = if options.hybrid {
+        let (v2_files, layers) = hash_files_v2(&files, piece_length)?;
+        (Some(v2::file_tree(&v2_files)), Some(layers))
+    } else {
+        (None, None)
+    };
+
+    let info = Info {
+        name: ByteBuf::from(name),
+        length: (!metadata.is_dir()).then_some(total_length as usize),
+        md5sum: None,
+        files: metadata.is_dir().then_some(entries),
+        piece_length,
+        pieces: Some(hash_pieces(&parts, piece_length)?),
+        meta_version: options.hybrid.then_some(2),
+        file_tree,
+        private: options.private.then_some(1),
+        source: options.source.clone(),
+        extra: BTreeMap::new(),
+    };
+
+    let urls: Vec<&String> = options.trackers.iter().flatten().collect();
+    Ok(MetaInfo {
+        announce: urls.first().map(|url| url.to_string()),
+        announce_list: (urls.len() > 1).then(|| options.trackers.clone()),
+        comment: options.comment.clone(),
+        created_by: options.created_by.clone(),
+        creation_date: options.creation_date,
+        url_list: (!options.web_seeds.is_empty())
+            .then(|| OneOrMany::Many(options.web_seeds.clone())),
+        encoding: None,
+        httpseeds: None,
+        info,
+        piece_layers,
+        extra: BTreeMap::new(),
+    })
+}
+
+/// [`create_metainfo`], bencoded.
+pub fn create(path: &Path, options: &CreateOptions) -> anyhow::Result<Vec<u8>> {
+    let meta = create_metainfo(path, options)?;
+    bencode::to_bytes(&meta).context("encode metainfo")
+}
+
+/// Collect the regular files below `dir` as path components and lengths,
+/// sorted by path.
+fn collect_files(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    files: &mut Vec<(Vec<String>, usize)>,
+) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow::anyhow!("file name {:?} is not UTF-8", name))?;
+        let metadata = fs::metadata(entry.path())?;
+        prefix.push(name);
+        if metadata.is_dir() {
+            collect_files(&entry.path(), prefix, files)?;
+        } else {
+            files.push((prefix.clone(), metadata.len() as usize));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// SHA-1 every `piece_length` bytes of the files read back to back. A part
+/// without a path is padding of that many zeros.
+fn hash_pieces(parts: &[(Option<PathBuf>, usize)], piece_length: usize) -> anyhow::Result<Vec<u8>> {
+    let mut pieces = Vec::new();
+    let mut piece = Vec::with_capacity(piece_length);
+    for (path, length) in parts {
+        let mut file: Box<dyn Read> = match path {
+            Some(path) => Box::new(
+                File::open(path).with_context(|| format!("could not open {}", path.display()))?,
+            ),
+            None => Box::new(io::repeat(0).take(*length as u64)),
+        };
+        loop {
+            let read = (&mut file)
+                .take((piece_length - piece.len()) as u64)
+                .read_to_end(&mut piece)?;
+            if piece.len() == piece_length {
+                pieces.extend_from_slice(&Sha1::digest(&piece));
+                piece.clear();
+            }
+            if read == 0 {
+                break;
+            }
+        }
+    }
+    if !piece.is_empty() {
+        pieces.extend_from_slice(&Sha1::digest(&piece));
+    }
+    Ok(pieces)
+}
+
+/// Hash each file into its v2 merkle tree, returning the files for the
+/// `file tree` and the `piece layers` of those longer than a piece.
+fn hash_files_v2(
+    files: &[(Vec<String>, PathBuf, usize)],
+    piece_length: usize,
+) -> anyhow::Result<(Vec<V2File>, BTreeMap<ByteBuf, ByteBuf>)> {
+    let mut v2_files = Vec::new();
+    let mut layers = BTreeMap::new();
+    for (components, on_disk, length) in files {
+        let mut file =
+            File::open(on_disk).with_context(|| format!("could not open {}", on_disk.display()))?;
+        let mut leaves = Vec::new();
+        let mut block = Vec::with_capacity(BLOCK_SIZE);
+        loop {
+            block.clear();
+            (&mut file)
+                .take(BLOCK_SIZE as u64)
+                .read_to_end(&mut block)?;
+            if block.is_empty() {
+                break;
+            }
+            leaves.push(Sha256::digest(&block));
+        }
+        if leaves.len() != length.div_ceil(BLOCK_SIZE) {
+            bail!("{} changed while it was hashed", on_disk.display());
+        }
+
+        let pieces_root = (!leaves.is_empty()).then(|| merkle::pieces_root(&leaves));
+        if let Some(root) = pieces_root.filter(|_| *length > piece_length) {
+            let layer = merkle::piece_layer(&leaves, piece_length).concat();
+            layers.insert(ByteBuf::from(root.to_vec()), ByteBuf::from(layer));
+        }
+        v2_files.push(V2File {
+            path: components.clone(),
+            length: *length,
+            pieces_root,
+            piece_layer: Vec::new(),
+        });
+    }
+    Ok((v2_files, layers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{Layout, Torrent};
+
+    #[test]
+    fn automatic_piece_length() {
+        assert_eq!(auto_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(1 << 30), 1 << 20);
+        assert_eq!(auto_piece_length(u64::MAX), MAX_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn single_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        fs::write(&path, b"abcdefghij").unwrap();
+        let options = CreateOptions {
+            piece_length: Some(4),
+            trackers: vec![vec!["http://t/announce".into()]],
+            comment: Some("hello".into()),
+            creation_date: Some(1_700_000_000),
+            private: true,
+            web_seeds: vec!["http://seed/".into()],
+            ..CreateOptions::default()
+        };
+        let bytes = create(&path, &options).unwrap();
+
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.name, "data.bin");
+        assert_eq!(torrent.url, "http://t/announce");
+        assert_eq!(torrent.length, 10);
+        assert_eq!(torrent.piece_count(), 3);
+        assert!(torrent.verify_piece(1, b"efgh"));
+        assert!(torrent.verify_piece(2, b"ij"));
+
+        let value = bencode::decode_strict(&bytes).unwrap();
+        assert_eq!(value.get_str("comment").unwrap(), "hello");
+        assert_eq!(value.get_int("info.private").unwrap(), 1);
+        assert!(value.get("announce-list").is_none());
+    }
+
+    #[test]
+    fn directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("pkg");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("b"), b"bbb").unwrap();
+        fs::write(root.join("a"), b"aa").unwrap();
+        fs::write(root.join("sub").join("c"), b"c").unwrap();
+        let options = CreateOptions {
+            piece_length: Some(4),
+            trackers: vec![vec!["http://a".into()], vec!["http://b".into()]],
+            ..CreateOptions::default()
+        };
+        let bytes = create(&root, &options).unwrap();
+
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.name, "pkg");
+        let Layout::MultiFile { files } = &torrent.layout else {
+            panic!("expected a multi-file layout");
+        };
+        let paths: Vec<String> = files
+            .iter()
+            .map(|file| file.lossy_path().join("/"))
+            .collect();
+        assert_eq!(paths, ["a", "b", "sub/c"]);
+        assert!(torrent.verify_piece(0, b"aabb"));
+        assert!(torrent.verify_piece(1, b"bc"));
+        assert_eq!(torrent.trackers.tiers().len(), 2);
+
+        fs::create_dir(dir.path().join("empty")).unwrap();
+        assert!(create(&dir.path().join("empty"), &options).is_err());
+    }
+
+    #[test]
+    fn hybrid_pads_files_to_piece_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("pkg");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a"), vec![1u8; BLOCK_SIZE + 5]).unwrap();
+        fs::write(root.join("b"), b"bbb").unwrap();
+        fs::write(root.join("c"), vec![3u8; 2 * BLOCK_SIZE]).unwrap();
+        let options = CreateOptions {
+            piece_length: Some(BLOCK_SIZE),
+            hybrid: true,
+            ..CreateOptions::default()
+        };
+        let torrent = Torrent::from_bytes(&create(&root, &options).unwrap()).unwrap();
+        assert_eq!(torrent.version, v2::MetaVersion::Hybrid);
+        assert!(torrent.info_hash_v2.is_some());
+
+        let Layout::MultiFile { files } = &torrent.layout else {
+            panic!("expected a multi-file layout");
+        };
+        let lengths: Vec<(usize, bool)> = files
+            .iter()
+            .map(|file| (file.length, file.is_padding()))
+            .collect();
+        let pad_a = BLOCK_SIZE - 5;
+        let pad_b = BLOCK_SIZE - 3;
+        assert_eq!(
+            lengths,
+            [
+                (BLOCK_SIZE + 5, false),
+                (pad_a, true),
+                (3, false),
+                (pad_b, true),
+                (2 * BLOCK_SIZE, false)
+            ]
+        );
+        assert_eq!(files[1].lossy_path(), [".pad", &pad_a.to_string()]);
+        assert_eq!(torrent.piece_count(), 5);
+        assert_eq!(torrent.v2_files.len(), 3);
+        assert_eq!(torrent.v2_files[2].piece_layer.len(), 2);
+        assert!(!torrent.to_string().contains(".pad"));
+        let data_length = BLOCK_SIZE + 5 + 3 + 2 * BLOCK_SIZE;
+        assert_eq!(torrent.layout.data_length(), data_length);
+        assert_eq!(torrent.length, data_length + pad_a + pad_b);
+        assert!(torrent
+            .to_string()
+            .contains(&format!("Length: {}\n", data_length)));
+
+        let mut padded_b = b"bbb".to_vec();
+        padded_b.resize(BLOCK_SIZE, 0);
+        assert!(torrent.verify_piece(2, &padded_b));
+
+        assert!(crate::verify::verify_hybrid(&torrent, &root)
+            .unwrap()
+            .is_complete());
+        let options = CreateOptions {
+            piece_length: Some(BLOCK_SIZE + 1),
+            ..options
+        };
+        assert!(create(&root, &options).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+//! # Edit
+//!
+//! change the keys of an existing torrent while keeping its `info`
+//! dictionary, and so its info hash, byte for byte
+//!
+
+use crate::bencode::{self, BValue};
+use crate::torrent::{decode_metainfo_with_info, InfoHash, Torrent};
+use anyhow::{bail, Context};
+use std::collections::BTreeMap;
+
+/// One change to a torrent. Keys are top-level keys such as `comment`, or
+/// `info.<key>` for a key inside the info dictionary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edit {
+    Set { key: String, value: BValue },
+    Remove { key: String },
+}
+
+impl Edit {
+    pub fn set(key: &str, value: BValue) -> Edit {
+        Edit::Set {
+            key: key.to_string(),
+            value,
+        }
+    }
+
+    pub fn remove(key: &str) -> Edit {
+        Edit::Remove {
+            key: key.to_string(),
+        }
+    }
+
+    /// Replace the trackers: `announce` becomes the first URL and
+    /// `announce-list` is written only for more than one URL.
+    pub fn trackers(tiers: &[Vec<String>]) -> anyhow::Result<Vec<Edit>> {
+        let urls: Vec<&String> = tiers.iter().flatten().collect();
+        let announce = match urls.first() {
+            Some(url) => Edit::set("announce", bencode::to_value(url)?),
+            None => Edit::remove("announce"),
+        };
+        let announce_list = if urls.len() > 1 {
+            Edit::set("announce-list", bencode::to_value(tiers)?)
+        } else {
+            Edit::remove("announce-list")
+        };
+        Ok(vec![announce, announce_list])
+    }
+
+    /// Replace the web seeds in `url-list`, removing it when empty.
+    pub fn web_seeds(urls: &[String]) -> anyhow::Result<Edit> {
+        if urls.is_empty() {
+            return Ok(Edit::remove("url-list"));
+        }
+        Ok(Edit::set("url-list", bencode::to_value(urls)?))
+    }
+
+    pub fn key(&self) -> &str {
+        match self {
+            Edit::Set { key, .. } | Edit::Remove { key } => key,
+        }
+    }
+
+    /// The key inside `info` this edit touches, `Some("")` for `info`
+    /// itself, or `None` for other top-level keys.
+    fn info_key(&self) -> Option<&str> {
+        match self.key() {
+            "info" => Some(""),
+            key => key.strip_prefix("info."),
+        }
+    }
+}
+
+/// A rewritten torrent.
+#[derive(Debug)]
+pub struct Edited {
+    pub bytes: Vec<u8>,
+    pub old_info_hash: InfoHash,
+    pub new_info_hash: InfoHash,
+}
+
+/// Apply `edits` to the torrent in `bytes`. Edits inside `info` change the
+/// info hash and are refused unless `allow_info_changes` is set; without
+/// them the original `info` bytes are copied unchanged, even if they are
+/// not canonical bencode.
+pub fn edit(bytes: &[u8], edits: &[Edit], allow_info_changes: bool) -> anyhow::Result<Edited> {
+    let (value, info_span) = decode_metainfo_with_info(bytes)?;
+    let old_info_hash = Torrent::from_decoded(&value, &bytes[info_span.clone()])?.info_hash;
+    let BValue::Dict(mut top) = value else {
+        bail!("the torrent is not a dictionary");
+    };
+    let mut info = match top.remove(&b"info"[..]) {
+        Some(BValue::Dict(info)) => info,
+        _ => bail!("info is not a dictionary"),
+    };
+
+    let mut info_changed = false;
+    for edit in edits {
+        let (map, key) = match edit.info_key() {
+            Some("") => bail!("the info dictionary can only be edited key by key"),
+            Some(key) => {
+                if !allow_info_changes {
+                    bail!(
+                        "editing `{}` would change the info hash; allow info changes to do it anyway",
+                        edit.key()
+                    );
+                }
+                info_changed = true;
+                (&mut info, key)
+            }
+            None => (&mut top, edit.key()),
+        };
+        match edit {
+            Edit::Set { value, .. } => {
+                map.insert(key.as_bytes().to_vec(), value.clone());
+            }
+            Edit::Remove { .. } => {
+                map.remove(key.as_bytes());
+            }
+        }
+    }
+
+    let info_bytes = if info_changed {
+        BValue::Dict(info).encode()
+    } else {
+        bytes[info_span].to_vec()
+    };
+    let bytes = encode_with_info(&top, &info_bytes);
+    let new_info_hash = Torrent::from_bytes(&bytes)
+        .context("the edited torrent is invalid")?
+        .info_hash;
+    Ok(Edited {
+        bytes,
+        old_info_hash,
+        new_info_hash,
+    })
+}
+
+/// Encode the top-level dictionary with `info` spliced in as raw bytes at
+/// its sorted position.
+fn encode_with_info(top: &BTreeMap<Vec<u8>, BValue>, info: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![b'd'];
+    let mut info_written = false;
+    for (key, value) in top {
+        if !info_written && key.as_slice() > &b"info"[..] {
+            bytes.extend_from_slice(b"4:info");
+            bytes.extend_from_slice(info);
+            info_written = true;
+        }
+        bytes.extend(BValue::Bytes(key.clone()).encode());
+        bytes.extend(value.encode());
+    }
+    if !info_written {
+        bytes.extend_from_slice(b"4:info");
+        bytes.extend_from_slice(info);
+    }
+    bytes.push(b'e');
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::Limits;
+
+    // `info` is not canonical (unsorted keys) so re-encoding it would change
+    // the hash
+    const TORRENT: &[u8] = b"d8:announce5:old:/7:comment3:old4:infod4:name1:a6:lengthi3e12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-list2:u1e";
+
+    #[test]
+    fn top_level_edits_keep_info_bytes() {
+        let mut edits = Edit::trackers(&[vec!["http://a".into(), "http://b".into()]]).unwrap();
+        edits.push(Edit::set("comment", BValue::Bytes(b"new".to_vec())));
+        edits.push(Edit::web_seeds(&[]).unwrap());
+        edits.push(Edit::set("created by", BValue::Bytes(b"me".to_vec())));
+        let edited = edit(TORRENT, &edits, false).unwrap();
+
+        assert_eq!(edited.old_info_hash, edited.new_info_hash);
+        let info = bencode::raw_value(TORRENT, &["info"], Limits::torrent_file()).unwrap();
+        assert_eq!(
+            bencode::raw_value(&edited.bytes, &["info"], Limits::torrent_file()).unwrap(),
+            info
+        );
+        let torrent = Torrent::from_bytes(&edited.bytes).unwrap();
+        assert_eq!(torrent.meta.comment.as_deref(), Some("new"));
+        assert_eq!(torrent.meta.created_by.as_deref(), Some("me"));
+        assert!(torrent.meta.url_list.is_none());
+        assert_eq!(torrent.trackers.urls().count(), 2);
+    }
+
+    #[test]
+    fn info_edits_need_permission() {
+        let edits = [Edit::set("info.source", BValue::Bytes(b"x".to_vec()))];
+        let err = edit(TORRENT, &edits, false).unwrap_err();
+        assert!(err.to_string().contains("would change the info hash"));
+        assert!(edit(TORRENT, &[Edit::remove("info")], true).is_err());
+
+        let edited = edit(TORRENT, &edits, true).unwrap();
+        assert_ne!(edited.old_info_hash, edited.new_info_hash);
+        let torrent = Torrent::from_bytes(&edited.bytes).unwrap();
+        assert_eq!(torrent.meta.info.source.as_deref(), Some("x"));
+    }
+
+    #[test]
+    fn info_is_placed_in_key_order() {
+        let top = BTreeMap::from([
+            (b"a".to_vec(), BValue::Int(1)),
+            (b"z".to_vec(), BValue::Int(2)),
+        ]);
+        assert_eq!(encode_with_info(&top, b"de"), b"d1:ai1e4:infode1:zi2ee");
+        assert_eq!(encode_with_info(&BTreeMap::new(), b"de"), b"d4:infodee");
+    }
+}
@@ -1,2 +1,12 @@
 pub mod bencode;
+pub mod create;
+pub mod edit;
+pub mod magnet;
+pub mod merkle;
+pub mod metadata;
+pub mod peers;
+pub mod sha256;
 pub mod torrent;
+pub mod tracker;
+pub mod v2;
+pub mod verify;
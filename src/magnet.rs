@@ -0,0 +1,263 @@
+//! # Magnet
+//!
+//! parse and generate `magnet:` URIs for BitTorrent (BEP 9)
+//!
+
+use crate::torrent::{InfoHash, ParseInfoHashError, Torrent};
+use crate::v2::InfoHashV2;
+use std::fmt;
+use std::str::FromStr;
+
+/// A BitTorrent magnet link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    /// From `xt=urn:btih:`, in hex or base32. A v2-only link has no btih,
+    /// so this is the truncated v2 hash, which is what its peers use.
+    pub info_hash: InfoHash,
+    /// From `xt=urn:btmh:`, the SHA-256 info hash of a v2 or hybrid
+    /// torrent (BEP 52).
+    pub info_hash_v2: Option<InfoHashV2>,
+    /// `dn`, a name to show until the metadata arrives.
+    pub display_name: Option<String>,
+    /// Every `tr`, in order.
+    pub trackers: Vec<String>,
+    /// `x.pe` peer addresses, as `host:port`.
+    pub peers: Vec<String>,
+    /// `ws` web seeds.
+    pub web_seeds: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MagnetError {
+    #[error("not a magnet link, expected `magnet:?`")]
+    Scheme,
+    #[error("invalid magnet query: {0}")]
+    Query(String),
+    #[error("magnet link has no `xt=urn:btih:` or `xt=urn:btmh:` info hash")]
+    MissingInfoHash,
+    #[error("`xt=urn:btmh:` must be a SHA-256 multihash, 1220 and 64 hex characters")]
+    Multihash,
+    #[error(transparent)]
+    InfoHash(#[from] ParseInfoHashError),
+}
+
+const BTIH: &str = "urn:btih:";
+const BTMH: &str = "urn:btmh:";
+/// The multihash prefix of a SHA-256 digest: code 0x12, length 0x20.
+const SHA256_MULTIHASH: &str = "1220";
+
+impl FromStr for Magnet {
+    type Err = MagnetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let query = s.strip_prefix("magnet:?").ok_or(MagnetError::Scheme)?;
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).map_err(|e| MagnetError::Query(e.to_string()))?;
+
+        let mut info_hash = None;
+        let mut magnet = Magnet {
+            info_hash: InfoHash([0; 20]),
+            info_hash_v2: None,
+            display_name: None,
+            trackers: Vec::new(),
+            peers: Vec::new(),
+            web_seeds: Vec::new(),
+        };
+        for (key, value) in params {
+            // parameters may carry an index suffix, as in `tr.1`
+            let key = match key.split_once('.') {
+                Some((base, index)) if index.bytes().all(|b| b.is_ascii_digit()) => base,
+                _ => &key,
+            };
+            match key {
+                "xt" => {
+                    // other urns are skipped
+                    if let Some(hash) = value.strip_prefix(BTIH) {
+                        if info_hash.is_none() {
+                            info_hash = Some(hash.parse()?);
+                        }
+                    } else if let Some(hash) = value.strip_prefix(BTMH) {
+                        if magnet.info_hash_v2.is_none() {
+                            magnet.info_hash_v2 = Some(parse_multihash(hash)?);
+                        }
+                    }
+                }
+                "dn" => magnet.display_name = Some(value),
+                "tr" => magnet.trackers.push(value),
+                "x.pe" => magnet.peers.push(value),
+                "ws" => magnet.web_seeds.push(value),
+                _ => {}
+            }
+        }
+        magnet.info_hash = info_hash
+            .or(magnet.info_hash_v2.map(|hash| hash.truncated()))
+            .ok_or(MagnetError::MissingInfoHash)?;
+        Ok(magnet)
+    }
+}
+
+fn parse_multihash(s: &str) -> Result<InfoHashV2, MagnetError> {
+    let digest = s
+        .strip_prefix(SHA256_MULTIHASH)
+        .ok_or(MagnetError::Multihash)?;
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(digest, &mut bytes).map_err(|_| MagnetError::Multihash)?;
+    Ok(InfoHashV2(bytes))
+}
+
+impl fmt::Display for Magnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "magnet:?")?;
+        if !self.is_v2_only() {
+            write!(f, "xt={}{}", BTIH, self.info_hash)?;
+        }
+        if let Some(hash) = &self.info_hash_v2 {
+            if !self.is_v2_only() {
+                write!(f, "&")?;
+            }
+            write!(f, "xt={}{}{}", BTMH, SHA256_MULTIHASH, hash)?;
+        }
+        let params = self
+            .display_name
+            .iter()
+            .map(|name| ("dn", name))
+            .chain(self.trackers.iter().map(|url| ("tr", url)))
+            .chain(self.peers.iter().map(|peer| ("x.pe", peer)))
+            .chain(self.web_seeds.iter().map(|url| ("ws", url)));
+        for param in params {
+            let encoded = serde_urlencoded::to_string([param]).map_err(|_| fmt::Error)?;
+            write!(f, "&{}", encoded)?;
+        }
+        Ok(())
+    }
+}
+
+impl Magnet {
+    /// The first tracker, if any.
+    pub fn tracker(&self) -> Option<&str> {
+        self.trackers.first().map(String::as_str)
+    }
+
+    /// Whether the link has only a v2 info hash, and so no btih.
+    pub fn is_v2_only(&self) -> bool {
+        self.info_hash_v2.map(|hash| hash.truncated()) == Some(self.info_hash)
+    }
+
+    /// A link for `torrent`, listing all its trackers tier by tier. A v2
+    /// torrent gets a btmh, and a hybrid one both a btih and a btmh.
+    pub fn from_torrent(torrent: &Torrent) -> Magnet {
+        Magnet {
+            info_hash: torrent.info_hash,
+            info_hash_v2: torrent.info_hash_v2,
+            display_name: Some(torrent.name.clone()),
+            trackers: torrent.trackers.urls().map(str::to_string).collect(),
+            peers: Vec::new(),
+            web_seeds: torrent.web_seeds.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "ad42ce8109f54c99613ce38f9b4d87e70f24a165";
+
+    #[test]
+    fn parse_magnet() {
+        let magnet: Magnet = format!(
+            "magnet:?xt=urn:btih:{}&dn=magnet1.gif&tr=http%3A%2F%2Fa%2Fannounce&tr.1=udp://b:80\
+             &x.pe=10.0.0.1:6881&ws=http%3A%2F%2Fseed%2F&foo=bar",
+            HEX
+        )
+        .parse()
+        .unwrap();
+        assert_eq!(magnet.info_hash.to_string(), HEX);
+        assert_eq!(magnet.display_name.as_deref(), Some("magnet1.gif"));
+        assert_eq!(magnet.trackers, ["http://a/announce", "udp://b:80"]);
+        assert_eq!(magnet.tracker(), Some("http://a/announce"));
+        assert_eq!(magnet.peers, ["10.0.0.1:6881"]);
+        assert_eq!(magnet.web_seeds, ["http://seed/"]);
+
+        let base32: Magnet = format!("magnet:?xt=urn:btih:{}", magnet.info_hash.to_base32())
+            .parse()
+            .unwrap();
+        assert_eq!(base32.info_hash, magnet.info_hash);
+        assert!(base32.trackers.is_empty());
+    }
+
+    #[test]
+    fn invalid_magnets() {
+        assert!(matches!(
+            "http://x".parse::<Magnet>(),
+            Err(MagnetError::Scheme)
+        ));
+        assert!(matches!(
+            "magnet:?dn=x".parse::<Magnet>(),
+            Err(MagnetError::MissingInfoHash)
+        ));
+        assert!(matches!(
+            "magnet:?xt=urn:btih:abc".parse::<Magnet>(),
+            Err(MagnetError::InfoHash(_))
+        ));
+        // a SHA-1 multihash
+        assert!(matches!(
+            format!("magnet:?xt=urn:btmh:1114{}", HEX).parse::<Magnet>(),
+            Err(MagnetError::Multihash)
+        ));
+    }
+
+    #[test]
+    fn generate_round_trips() {
+        let magnet = Magnet {
+            info_hash: HEX.parse().unwrap(),
+            info_hash_v2: None,
+            display_name: Some("a b&c".into()),
+            trackers: vec!["http://a/announce?x=1".into(), "udp://b:80".into()],
+            peers: vec!["[::1]:6881".into()],
+            web_seeds: vec!["http://seed/".into()],
+        };
+        let link = magnet.to_string();
+        assert!(link.starts_with(&format!("magnet:?xt=urn:btih:{}&dn=a+b%26c&tr=", HEX)));
+        assert_eq!(link.parse::<Magnet>().unwrap(), magnet);
+    }
+
+    #[test]
+    fn v2_and_hybrid_links() {
+        use crate::create::{create, CreateOptions};
+        use crate::merkle::BLOCK_SIZE;
+        use crate::v2::{self, MetaVersion};
+
+        let bytes = v2::test_metainfo(&[("a", b"abc")], BLOCK_SIZE);
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.version, MetaVersion::V2);
+        let v2_hash = torrent.info_hash_v2.unwrap();
+        let magnet = Magnet::from_torrent(&torrent);
+        assert!(magnet.is_v2_only());
+        let link = magnet.to_string();
+        assert!(link.starts_with(&format!("magnet:?xt=urn:btmh:1220{}&dn=", v2_hash)));
+        assert!(!link.contains(BTIH));
+        let parsed: Magnet = link.parse().unwrap();
+        assert_eq!(parsed, magnet);
+        assert_eq!(parsed.info_hash, v2_hash.truncated());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        std::fs::write(&path, b"abc").unwrap();
+        let options = CreateOptions {
+            hybrid: true,
+            ..CreateOptions::default()
+        };
+        let torrent = Torrent::from_bytes(&create(&path, &options).unwrap()).unwrap();
+        assert_eq!(torrent.version, MetaVersion::Hybrid);
+        let hybrid = Magnet::from_torrent(&torrent);
+        assert!(!hybrid.is_v2_only());
+        let link = hybrid.to_string();
+        assert!(link.starts_with(&format!(
+            "magnet:?xt=urn:btih:{}&xt=urn:btmh:1220{}&dn=",
+            torrent.info_hash,
+            torrent.info_hash_v2.unwrap()
+        )));
+        assert_eq!(link.parse::<Magnet>().unwrap(), hybrid);
+    }
+}
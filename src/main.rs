@@ -1,17 +1,26 @@
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 #[allow(unused_imports)]
 use std::net::{TcpStream, ToSocketAddrs};
 use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 // external crates
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
-use serde_json::Value;
 // Available if you need it!
 // use serde_bencode
-use bittorrent_starter_rust::bencode::Bencode;
+use bittorrent_starter_rust::bencode::{self, Bencode, ToBencode};
+use bittorrent_starter_rust::create::{self, CreateOptions};
+use bittorrent_starter_rust::edit::{self, Edit};
+use bittorrent_starter_rust::magnet::Magnet;
+use bittorrent_starter_rust::metadata::{self, MetadataPeer};
+use bittorrent_starter_rust::peers::PeerHints;
 use bittorrent_starter_rust::torrent::*;
+use bittorrent_starter_rust::tracker;
+use bittorrent_starter_rust::v2::MetaVersion;
+use bittorrent_starter_rust::verify::{self, PieceStatus, VerifyReport};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -24,13 +33,33 @@ struct Args {
 #[clap(rename_all = "snake_case")]
 enum Commands {
     Decode {
-        value: String,
+        value: OsString,
+        /// Print only the value at this path, e.g. `info.files[0].length`
+        #[arg(long)]
+        query: Option<String>,
     },
+    /// Read JSON from stdin and write it bencoded to stdout
+    Encode,
     Info {
         torrent: PathBuf,
+        /// Print only the metainfo value at this path, e.g. `info.piece length`;
+        /// strings and integers are printed bare, other values as JSON
+        #[arg(long)]
+        field: Option<String>,
+        /// Print the whole metainfo as JSON
+        #[arg(long, conflicts_with = "field")]
+        json: bool,
+    },
+    /// Report every non-canonical construct in a torrent file
+    Lint {
+        torrent: PathBuf,
     },
     Peers {
         torrent: PathBuf,
+        /// Ask every tracker and merge their peers instead of stopping at
+        /// the first that answers
+        #[arg(long)]
+        all: bool,
     },
     Handshake {
         torrent: PathBuf,
@@ -43,9 +72,106 @@ enum Commands {
         piece: usize,
     },
     Download {
+        /// Output file, or top directory for a multi-file torrent
+        #[arg(short)]
+        output: PathBuf,
+        torrent: PathBuf,
+    },
+    /// Print the tracker and info hash of a magnet link
+    MagnetParse {
+        link: String,
+    },
+    /// Handshake with the first peer of a magnet link, including the
+    /// extension handshake
+    MagnetHandshake {
+        link: String,
+    },
+    /// Fetch the metadata of a magnet link from peers and print it
+    MagnetInfo {
+        link: String,
+    },
+    /// Fetch the metadata of a magnet link, then download its files
+    MagnetDownload {
+        /// Output file, or top directory for a multi-file torrent
         #[arg(short)]
         output: PathBuf,
+        link: String,
+    },
+    /// Print a magnet link for a torrent
+    Magnet {
+        torrent: PathBuf,
+    },
+    /// Check downloaded data against the torrent's piece hashes
+    Verify {
         torrent: PathBuf,
+        /// The downloaded file, or top directory for a multi-file torrent
+        path: PathBuf,
+    },
+    /// Change the trackers, web seeds or comment of a torrent while keeping
+    /// its info hash
+    Edit {
+        /// Where to write the edited torrent
+        #[arg(short)]
+        output: PathBuf,
+        torrent: PathBuf,
+        /// Replace the trackers; repeat for further tiers, or give a
+        /// comma-separated list for several trackers in one tier
+        #[arg(long)]
+        announce: Vec<String>,
+        /// Remove all trackers
+        #[arg(long, conflicts_with = "announce")]
+        no_announce: bool,
+        /// Replace the web seeds; may be repeated
+        #[arg(long)]
+        web_seed: Vec<String>,
+        /// Remove all web seeds
+        #[arg(long, conflicts_with = "web_seed")]
+        no_web_seed: bool,
+        #[arg(long)]
+        comment: Option<String>,
+        #[arg(long)]
+        created_by: Option<String>,
+        /// Remove a top-level key, or `info.<key>`; may be repeated
+        #[arg(long)]
+        remove: Vec<String>,
+        /// Set `info.source`, which changes the info hash
+        #[arg(long)]
+        source: Option<String>,
+        /// Permit edits inside `info`, which change the info hash
+        #[arg(long)]
+        allow_info_change: bool,
+    },
+    /// Hash a file or directory into a new torrent
+    Create {
+        /// Where to write the `.torrent` file
+        #[arg(short)]
+        output: PathBuf,
+        path: PathBuf,
+        /// Tracker URL; repeat for further tiers, or give a comma-separated
+        /// list for several trackers in one tier
+        #[arg(long)]
+        announce: Vec<String>,
+        /// Bytes per piece, chosen from the total size when omitted
+        #[arg(long)]
+        piece_length: Option<usize>,
+        #[arg(long)]
+        comment: Option<String>,
+        #[arg(long, default_value = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))]
+        created_by: String,
+        /// Leave out the creation date, for reproducible output
+        #[arg(long)]
+        no_date: bool,
+        #[arg(long)]
+        private: bool,
+        /// Value for `info.source`
+        #[arg(long)]
+        source: Option<String>,
+        /// Web seed URL; may be repeated
+        #[arg(long)]
+        web_seed: Vec<String>,
+        /// Write a hybrid v1/v2 torrent with padded files
+        #[arg(long)]
+        hybrid: bool,
     },
 }
 
@@ -54,53 +180,105 @@ async fn main() -> Result<()> {
     let args = Args::parse();
 
     match args.command {
-        Commands::Decode { value } => {
-            let encoded_value = value;
-            let decoded_value = encoded_value.bdecode();
-            println!("{}", decoded_value);
+        Commands::Decode { value, query } => {
+            let encoded_value = value.as_encoded_bytes();
+            let (decoded_value, rest) = encoded_value
+                .bdecode_each()
+                .context("could not decode the value")?;
+            if !rest.is_empty() {
+                eprintln!(
+                    "ignoring {} bytes of trailing data at byte {}",
+                    rest.len(),
+                    encoded_value.len() - rest.len()
+                );
+            }
+            match query {
+                Some(path) => println!("{}", decoded_value.get_path(&path)?),
+                None => println!("{}", decoded_value),
+            }
         }
-        Commands::Info { torrent } => {
+        Commands::Encode => {
+            let value: serde_json::Value =
+                serde_json::from_reader(io::stdin()).context("could not read JSON from stdin")?;
+            let encoded = value.to_bencode().context("could not encode the value")?;
+            io::stdout().write_all(&encoded)?;
+        }
+        Commands::Info {
+            torrent,
+            field,
+            json,
+        } => {
             let file_path = torrent;
             let mut f = File::open(file_path).context("could not open the info file")?;
             let mut buffer: Vec<u8> = Vec::new();
             f.read_to_end(&mut buffer)
                 .context("could not read the info file")?;
-            let decoded_value = buffer.bdecode();
-            println!("{}", Torrent::new(&decoded_value)?);
+            if let Some(path) = field {
+                let decoded_value =
+                    decode_metainfo(&buffer).context("could not decode the info file")?;
+                match decoded_value.get_path(&path)?.to_json() {
+                    serde_json::Value::String(s) => println!("{}", s),
+                    json => println!("{}", json),
+                }
+                return Ok(());
+            }
+            let torrent = Torrent::from_bytes(&buffer).context("could not parse the info file")?;
+            for warning in &torrent.warnings {
+                eprintln!("warning: {}", warning);
+            }
+            if json {
+                println!("{}", serde_json::to_string_pretty(&torrent.to_json()?)?);
+            } else {
+                println!("{}", torrent);
+            }
         }
-        Commands::Peers { torrent } => {
+        Commands::Lint { torrent } => {
+            let buffer = std::fs::read(&torrent).context("could not read the torrent file")?;
+            let report = bencode::lint(&buffer, bencode::Limits::torrent_file());
+            report
+                .violations
+                .iter()
+                .for_each(|violation| println!("{}", violation));
+            if let Some(error) = &report.error {
+                println!("{}", error);
+            }
+            if !report.is_clean() {
+                anyhow::bail!("{} is not canonical bencode", torrent.display());
+            }
+            println!("{} is canonical bencode", torrent.display());
+        }
+        Commands::Peers { torrent, all } => {
             let file_path = torrent;
             let mut f = File::open(file_path)?;
             let mut buffer: Vec<u8> = Vec::new();
             f.read_to_end(&mut buffer)?;
-            let decoded_value = buffer.bdecode();
-            let torrent = Torrent::new(&decoded_value)?;
-
-            let left = torrent.piece_length.to_string();
-            let query_params = vec![
-                ("uploaded", "0"),
-                ("downloaded", "0"),
-                ("compact", "1"),
-                ("left", &left),
-                ("peer_id", "00112233445566778899"),
-                ("port", "6881"),
-            ];
+            let mut torrent =
+                Torrent::from_bytes(&buffer).context("could not parse the torrent file")?;
 
-            let url_with_query = format!(
-                "{}?{}&info_hash={}",
-                torrent.url,
-                serde_urlencoded::to_string(query_params)?,
-                torrent.info_hash.to_url()
-            );
-
-            let resp = reqwest::blocking::get(url_with_query)?;
-            let body = resp.bytes()?;
-            let decoded = body.to_vec().bdecode();
+            let responses = if all {
+                let mut responses = Vec::new();
+                for (url, response) in torrent.announce_all() {
+                    match response {
+                        Ok(response) => {
+                            eprintln!("Tracker: {} ({} peers)", url, response.peers.len());
+                            responses.push(response);
+                        }
+                        Err(error) => eprintln!("Tracker: {} failed: {:#}", url, error),
+                    }
+                }
+                if responses.is_empty() {
+                    anyhow::bail!("no tracker responded");
+                }
+                responses
+            } else {
+                let (url, response) = torrent.announce()?;
+                eprintln!("Tracker: {}", url);
+                vec![response]
+            };
 
-            if let Value::Array(vec) = &decoded["peers"] {
-                vec.iter()
-                    .for_each(|map| println!("{}:{}", map["ip"].as_str().unwrap(), map["port"]));
-            }
+            tracker::merge_peers(&responses)
+                .iter()
+                .for_each(|peer| println!("{}", peer));
         }
         Commands::Handshake { torrent, peer } => {
             let file_path = torrent;
@@ -108,10 +286,7 @@ async fn main() -> Result<()> {
             let mut f = File::open(file_path)?;
             let mut buffer: Vec<u8> = Vec::new();
             f.read_to_end(&mut buffer)?;
-            let message = {
-                let decoded_value = buffer.bdecode();
-                Torrent::new(&decoded_value)?.to_handshake().to_message()
-            };
+            let message = Torrent::from_bytes(&buffer)?.to_handshake().to_message();
 
             let mut message_recevied = vec![0u8; message.len()]; // initialize message buffer
             let mut stream = TcpStream::connect(ip_address)?;
@@ -138,8 +313,7 @@ async fn main() -> Result<()> {
             let mut f = File::open(torrent)?;
             let mut buffer: Vec<u8> = Vec::new();
             f.read_to_end(&mut buffer)?;
-            let decoded_value = buffer.bdecode();
-            let torrent = Torrent::new(&decoded_value)?;
+            let mut torrent = Torrent::from_bytes(&buffer)?;
             let piece_received = torrent.download(piece)?;
             std::fs::write(&output, piece_received).context("save downloaded piece into file")?;
             eprintln!("File saved completed, path: {}", output.display());
@@ -151,13 +325,197 @@ async fn main() -> Result<()> {
             let mut f = File::open(torrent)?;
             let mut buffer: Vec<u8> = Vec::new();
             f.read_to_end(&mut buffer)?;
-            let decoded_value = buffer.bdecode();
-            let torrent = Torrent::new(&decoded_value)?;
+            let mut torrent = Torrent::from_bytes(&buffer)?;
             let piece_received = torrent.download_all()?;
-            std::fs::write(&output, piece_received).context("save downloaded piece into file")?;
+            torrent
+                .write_files(&piece_received, &output)
+                .context("save downloaded files")?;
+            eprintln!("File saved completed, path: {}", output.display());
+        }
+        Commands::MagnetParse { link } => {
+            let magnet: Magnet = link.parse().context("could not parse the magnet link")?;
+            if let Some(tracker) = magnet.tracker() {
+                println!("Tracker URL: {}", tracker);
+            }
+            println!("Info Hash: {}", magnet.info_hash);
+            if let Some(hash) = &magnet.info_hash_v2 {
+                println!("Info Hash v2: {}", hash);
+            }
+            if let Some(name) = &magnet.display_name {
+                println!("Name: {}", name);
+            }
+            magnet
+                .trackers
+                .iter()
+                .skip(1)
+                .for_each(|url| println!("Tracker URL: {}", url));
+            magnet
+                .peers
+                .iter()
+                .for_each(|peer| println!("Peer: {}", peer));
+            magnet
+                .web_seeds
+                .iter()
+                .for_each(|url| println!("Web Seed: {}", url));
+        }
+        Commands::MagnetHandshake { link } => {
+            let magnet: Magnet = link.parse().context("could not parse the magnet link")?;
+            let peers = metadata::magnet_peers(&magnet)?;
+            let peer = MetadataPeer::connect(&peers[0].to_string(), magnet.info_hash)?;
+            println!("Peer ID: {}", peer.handshake.peer_id_as_str());
+            if let Some(id) = peer.extensions.ut_metadata_id() {
+                println!("Peer Metadata Extension ID: {}", id);
+            }
+        }
+        Commands::MagnetInfo { link } => {
+            let magnet: Magnet = link.parse().context("could not parse the magnet link")?;
+            println!("{}", metadata::fetch_torrent(&magnet)?);
+        }
+        Commands::MagnetDownload { output, link } => {
+            let magnet: Magnet = link.parse().context("could not parse the magnet link")?;
+            let mut torrent = metadata::fetch_torrent(&magnet)?;
+            // a private torrent may only use its trackers (BEP 27), which
+            // is only known once the metadata has arrived
+            if torrent.is_private() {
+                eprintln!("skipping the magnet link's peers for a private torrent");
+            } else {
+                let hints = metadata::hinted_peers(&magnet);
+                torrent.add_peer_source(Box::new(PeerHints { peers: hints }));
+            }
+            let piece_received = torrent.download_all()?;
+            torrent
+                .write_files(&piece_received, &output)
+                .context("save downloaded files")?;
             eprintln!("File saved completed, path: {}", output.display());
         }
+        Commands::Magnet { torrent } => {
+            let buffer = std::fs::read(&torrent).context("could not read the torrent file")?;
+            let torrent =
+                Torrent::from_bytes(&buffer).context("could not parse the torrent file")?;
+            println!("{}", Magnet::from_torrent(&torrent));
+        }
+        Commands::Verify { torrent, path } => {
+            let buffer = std::fs::read(&torrent).context("could not read the torrent file")?;
+            let torrent =
+                Torrent::from_bytes(&buffer).context("could not parse the torrent file")?;
+            if torrent.version == MetaVersion::Hybrid {
+                let report = verify::verify_hybrid(&torrent, &path)?;
+                for (version, tree) in [("v1", &report.v1), ("v2", &report.v2)] {
+                    print_bad_pieces(&format!("{} piece", version), tree);
+                    println!("{}: {}", version, tree);
+                }
+                for inconsistency in &report.inconsistencies {
+                    println!("inconsistent: {}", inconsistency);
+                }
+                if !report.is_consistent() {
+                    anyhow::bail!("the v1 and v2 hashes of the torrent disagree");
+                }
+                if !report.is_complete() {
+                    anyhow::bail!("{} is incomplete", path.display());
+                }
+            } else {
+                let report = verify::verify(&torrent, &path)?;
+                print_bad_pieces("piece", &report);
+                println!("{}", report);
+                if !report.is_complete() {
+                    anyhow::bail!("{} is incomplete", path.display());
+                }
+            }
+        }
+        Commands::Edit {
+            output,
+            torrent,
+            announce,
+            no_announce,
+            web_seed,
+            no_web_seed,
+            comment,
+            created_by,
+            remove,
+            source,
+            allow_info_change,
+        } => {
+            let buffer = std::fs::read(&torrent).context("could not read the torrent file")?;
+            let mut edits = Vec::new();
+            if !announce.is_empty() || no_announce {
+                let tiers: Vec<Vec<String>> = announce
+                    .iter()
+                    .map(|tier| tier.split(',').map(str::to_string).collect())
+                    .collect();
+                edits.extend(Edit::trackers(&tiers)?);
+            }
+            if !web_seed.is_empty() || no_web_seed {
+                edits.push(Edit::web_seeds(&web_seed)?);
+            }
+            let strings = [
+                ("comment", comment),
+                ("created by", created_by),
+                ("info.source", source),
+            ];
+            for (key, value) in strings {
+                if let Some(value) = value {
+                    edits.push(Edit::set(key, bencode::BValue::Bytes(value.into_bytes())));
+                }
+            }
+            edits.extend(remove.iter().map(|key| Edit::remove(key)));
+
+            let edited = edit::edit(&buffer, &edits, allow_info_change)?;
+            std::fs::write(&output, &edited.bytes).context("could not write the torrent file")?;
+            println!("Old Info Hash: {}", edited.old_info_hash);
+            println!("New Info Hash: {}", edited.new_info_hash);
+        }
+        Commands::Create {
+            output,
+            path,
+            announce,
+            piece_length,
+            comment,
+            created_by,
+            no_date,
+            private,
+            source,
+            web_seed,
+            hybrid,
+        } => {
+            let creation_date = if no_date {
+                None
+            } else {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+                Some(now.as_secs() as i64)
+            };
+            let options = CreateOptions {
+                piece_length,
+                trackers: announce
+                    .iter()
+                    .map(|tier| tier.split(',').map(str::to_string).collect())
+                    .collect(),
+                comment,
+                created_by: Some(created_by),
+                creation_date,
+                private,
+                source,
+                web_seeds: web_seed,
+                hybrid,
+            };
+            let bytes = create::create(&path, &options)?;
+            std::fs::write(&output, &bytes).context("could not write the torrent file")?;
+            let torrent = Torrent::from_bytes(&bytes)?;
+            println!("Info Hash: {}", torrent.info_hash);
+            if let Some(hash) = torrent.info_hash_v2 {
+                println!("Info Hash v2: {}", hash);
+            }
+            eprintln!("Torrent saved, path: {}", output.display());
+        }
     }
 
     Ok(())
 }
+
+/// List the pieces of `report` that are bad or missing, one per line.
+fn print_bad_pieces(label: &str, report: &VerifyReport) {
+    for status in [PieceStatus::Bad, PieceStatus::Missing] {
+        for index in report.indices(status) {
+            println!("{} {}: {:?}", label, index, status);
+        }
+    }
+}
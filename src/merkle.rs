@@ -0,0 +1,108 @@
+//! # Merkle
+//!
+//! the per-file SHA-256 hash trees of BitTorrent v2 (BEP 52)
+//!
+
+use crate::sha256::Sha256;
+
+/// Leaves of the tree hash 16 KiB blocks; the last may be shorter.
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+pub type Hash = [u8; 32];
+
+/// SHA-256 of every 16 KiB block of `data`.
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_SIZE).map(Sha256::digest).collect()
+}
+
+pub fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize()
+}
+
+/// Root of the tree whose bottom layer is `nodes` followed by copies of
+/// `pad` up to `width`, a power of two. Each layer up pads with the hash of
+/// two pads below.
+pub fn root_padded(nodes: &[Hash], width: usize, pad: Hash) -> Hash {
+    debug_assert!(width.is_power_of_two() && nodes.len() <= width);
+    let mut layer = nodes.to_vec();
+    let mut pad = pad;
+    let mut width = width;
+    while width > 1 {
+        layer = (0..width.div_ceil(2).min(layer.len().div_ceil(2)))
+            .map(|i| {
+                let left = &layer[2 * i];
+                let right = layer.get(2 * i + 1).unwrap_or(&pad);
+                hash_pair(left, right)
+            })
+            .collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+/// The `pieces root` of a file from its block hashes: the tree is padded
+/// with zero leaves to a power of two.
+pub fn pieces_root(leaves: &[Hash]) -> Hash {
+    root_padded(leaves, leaves.len().next_power_of_two(), [0; 32])
+}
+
+/// Hashes of the subtrees covering each `piece_length` bytes, as stored in
+/// `piece layers`. The last piece is padded with zero leaves.
+pub fn piece_layer(leaves: &[Hash], piece_length: usize) -> Vec<Hash> {
+    let per_piece = piece_length / BLOCK_SIZE;
+    leaves
+        .chunks(per_piece)
+        .map(|chunk| root_padded(chunk, per_piece, [0; 32]))
+        .collect()
+}
+
+/// The root above a piece layer, which must equal the file's
+/// `pieces root`. Only files longer than one piece have a piece layer.
+pub fn root_from_piece_layer(layer: &[Hash], piece_length: usize) -> Hash {
+    let empty_piece = root_padded(&[], piece_length / BLOCK_SIZE, [0; 32]);
+    root_padded(layer, layer.len().next_power_of_two(), empty_piece)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(n: u8) -> Hash {
+        [n; 32]
+    }
+
+    #[test]
+    fn small_trees() {
+        assert_eq!(pieces_root(&[leaf(1)]), leaf(1));
+        assert_eq!(
+            pieces_root(&[leaf(1), leaf(2)]),
+            hash_pair(&leaf(1), &leaf(2))
+        );
+        let zero = [0; 32];
+        assert_eq!(
+            pieces_root(&[leaf(1), leaf(2), leaf(3)]),
+            hash_pair(&hash_pair(&leaf(1), &leaf(2)), &hash_pair(&leaf(3), &zero))
+        );
+        assert_eq!(block_hashes(&[7u8; BLOCK_SIZE + 1]).len(), 2);
+    }
+
+    #[test]
+    fn piece_layer_leads_to_the_same_root() {
+        let piece_length = 4 * BLOCK_SIZE;
+        for blocks in [3usize, 4, 5, 9, 17] {
+            let leaves: Vec<Hash> = (0..blocks).map(|n| leaf(n as u8 + 1)).collect();
+            let layer = piece_layer(&leaves, piece_length);
+            assert_eq!(layer.len(), blocks.div_ceil(4));
+            assert_eq!(
+                root_from_piece_layer(&layer, piece_length),
+                pieces_root(&leaves),
+                "{} blocks",
+                blocks
+            );
+        }
+    }
+}
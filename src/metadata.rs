@@ -0,0 +1,424 @@
+//! # Metadata
+//!
+//! fetch a torrent's info dictionary from peers with the extension
+//! protocol (BEP 10) and its `ut_metadata` extension (BEP 9)
+//!
+
+use crate::bencode::{self, Decoder};
+use crate::magnet::Magnet;
+use crate::sha256::Sha256;
+use crate::torrent::{
+    read_message, HandShake, InfoHash, PeerMessage, ToHandShake, ToPeerMessage, Torrent,
+};
+use crate::tracker::{self, Peer, TrackerTiers};
+use crate::v2::InfoHashV2;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// Metadata is exchanged in pieces of 16 KiB; only the last may be shorter.
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+/// The largest `metadata_size` a peer may announce.
+pub const MAX_METADATA_SIZE: usize = 64 * 1024 * 1024;
+/// The id we ask peers to use for `ut_metadata` messages sent to us.
+pub const LOCAL_UT_METADATA_ID: u8 = 1;
+/// Extended message id 0 is the extension handshake.
+const HANDSHAKE_ID: u8 = 0;
+
+const REQUEST: i64 = 0;
+const DATA: i64 = 1;
+const REJECT: i64 = 2;
+
+/// The bencoded dictionary of an extension handshake.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ExtensionHandshake {
+    /// Extension names mapped to the message ids the sender wants.
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtensionHandshake {
+    /// The handshake we send. It advertises only `ut_metadata`: no peer
+    /// exchange and no tracker list, so nothing about a private torrent's
+    /// swarm or trackers is shared.
+    pub fn local() -> Self {
+        ExtensionHandshake {
+            m: BTreeMap::from([("ut_metadata".to_string(), LOCAL_UT_METADATA_ID.into())]),
+            metadata_size: None,
+        }
+    }
+
+    /// The id the peer expects for `ut_metadata` messages, if it supports
+    /// them. An id of 0 disables the extension.
+    pub fn ut_metadata_id(&self) -> Option<u8> {
+        self.m
+            .get("ut_metadata")
+            .and_then(|&id| u8::try_from(id).ok())
+            .filter(|&id| id != 0)
+    }
+}
+
+/// The dictionary at the start of every `ut_metadata` message.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MetadataMessage {
+    pub msg_type: i64,
+    pub piece: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<usize>,
+}
+
+impl MetadataMessage {
+    pub fn request(piece: usize) -> Self {
+        MetadataMessage {
+            msg_type: REQUEST,
+            piece,
+            total_size: None,
+        }
+    }
+
+    /// Split a `ut_metadata` payload into its dictionary and the raw
+    /// piece data that follows it.
+    pub fn parse(payload: &[u8]) -> anyhow::Result<(MetadataMessage, &[u8])> {
+        let mut decoder = Decoder::new(payload);
+        let value = decoder
+            .decode_value()
+            .context("decode ut_metadata message")?;
+        let message = bencode::from_value(&value).context("read ut_metadata message")?;
+        Ok((message, decoder.remaining()))
+    }
+}
+
+/// Collects metadata pieces and checks the result against the info hash.
+#[derive(Debug)]
+pub struct MetadataAssembler {
+    info_hash: InfoHash,
+    total_size: usize,
+    pieces: Vec<Option<Vec<u8>>>,
+}
+
+impl MetadataAssembler {
+    pub fn new(info_hash: InfoHash, total_size: usize) -> anyhow::Result<Self> {
+        if total_size == 0 || total_size > MAX_METADATA_SIZE {
+            bail!("metadata size {} is out of range", total_size);
+        }
+        Ok(MetadataAssembler {
+            info_hash,
+            total_size,
+            pieces: vec![None; total_size.div_ceil(METADATA_PIECE_SIZE)],
+        })
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// Indices of the pieces not received yet.
+    pub fn missing(&self) -> Vec<usize> {
+        (0..self.pieces.len())
+            .filter(|&index| self.pieces[index].is_none())
+            .collect()
+    }
+
+    pub fn add(&mut self, index: usize, data: &[u8]) -> anyhow::Result<()> {
+        if index >= self.pieces.len() {
+            bail!("metadata piece {} out of range", index);
+        }
+        let expected = if index + 1 == self.pieces.len() {
+            self.total_size - index * METADATA_PIECE_SIZE
+        } else {
+            METADATA_PIECE_SIZE
+        };
+        if data.len() != expected {
+            bail!(
+                "metadata piece {} has {} bytes, expected {}",
+                index,
+                data.len(),
+                expected
+            );
+        }
+        self.pieces[index] = Some(data.to_vec());
+        Ok(())
+    }
+
+    /// The complete info dictionary, once every piece has arrived and the
+    /// whole matches the info hash.
+    pub fn finish(self) -> anyhow::Result<Vec<u8>> {
+        let mut metadata = Vec::with_capacity(self.total_size);
+        for (index, piece) in self.pieces.into_iter().enumerate() {
+            metadata.extend(piece.with_context(|| format!("metadata piece {} missing", index))?);
+        }
+        // a v2-only torrent is known by its truncated SHA-256 hash
+        let v1 = Sha1::digest(&metadata);
+        let v2 = InfoHashV2(Sha256::digest(&metadata)).truncated();
+        if v1.as_slice() != self.info_hash.as_bytes() && v2 != self.info_hash {
+            bail!("metadata does not match info hash {}", self.info_hash);
+        }
+        Ok(metadata)
+    }
+}
+
+/// A peer connection past the base and extension handshakes.
+pub struct MetadataPeer {
+    stream: TcpStream,
+    info_hash: InfoHash,
+    /// The peer's base handshake.
+    pub handshake: HandShake,
+    /// The peer's extension handshake.
+    pub extensions: ExtensionHandshake,
+}
+
+impl MetadataPeer {
+    /// Connect to `addr` and exchange the base and extension handshakes.
+    pub fn connect(addr: &str, info_hash: InfoHash) -> anyhow::Result<MetadataPeer> {
+        let mut stream = TcpStream::connect(addr).with_context(|| format!("connect {}", addr))?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let handshake = HandShake {
+            reserved: [0u8; 8],
+            info_hash,
+            peer_id: b"00112233445566778899".to_vec(),
+        }
+        .with_extensions();
+        let message = handshake.to_message();
+        stream.write_all(&message)?;
+        let mut reply = vec![0u8; message.len()];
+        stream.read_exact(&mut reply).context("read handshake")?;
+        let handshake = reply.to_handshake();
+        if handshake.info_hash != info_hash {
+            bail!("peer answered for another info hash");
+        }
+        if !handshake.supports_extensions() {
+            bail!("peer does not support the extension protocol");
+        }
+
+        let payload = bencode::to_bytes(&ExtensionHandshake::local())?;
+        stream.write_all(&PeerMessage::extended(HANDSHAKE_ID, &payload).to_message())?;
+
+        let mut peer = MetadataPeer {
+            stream,
+            info_hash,
+            handshake,
+            extensions: ExtensionHandshake::default(),
+        };
+        let payload = peer.next_extended(HANDSHAKE_ID)?;
+        let value = Decoder::new(&payload)
+            .decode_value()
+            .context("decode extension handshake")?;
+        peer.extensions = bencode::from_value(&value).context("read extension handshake")?;
+        Ok(peer)
+    }
+
+    /// Request every metadata piece and return the verified info
+    /// dictionary.
+    pub fn fetch_metadata(&mut self) -> anyhow::Result<Vec<u8>> {
+        let id = self
+            .extensions
+            .ut_metadata_id()
+            .context("peer does not support ut_metadata")?;
+        let size = self
+            .extensions
+            .metadata_size
+            .context("peer did not announce the metadata size")?;
+        let mut assembler = MetadataAssembler::new(self.info_hash, size)?;
+
+        for index in assembler.missing() {
+            let request = bencode::to_bytes(&MetadataMessage::request(index))?;
+            self.stream
+                .write_all(&PeerMessage::extended(id, &request).to_message())?;
+            let payload = self.next_extended(LOCAL_UT_METADATA_ID)?;
+            let (message, data) = MetadataMessage::parse(&payload)?;
+            match message.msg_type {
+                DATA if message.piece == index => assembler.add(index, data)?,
+                DATA => bail!("asked for metadata piece {}, got {}", index, message.piece),
+                REJECT => bail!("peer rejected metadata piece {}", index),
+                other => bail!("unexpected ut_metadata message type {}", other),
+            }
+            eprintln!(
+                "Metadata: {} of {} pieces",
+                index + 1,
+                assembler.piece_count()
+            );
+        }
+        assembler.finish()
+    }
+
+    /// Skip messages until an extended message with id `id` arrives and
+    /// return what follows the id.
+    fn next_extended(&mut self, id: u8) -> anyhow::Result<Vec<u8>> {
+        loop {
+            let message = read_message(&mut self.stream)?;
+            if let Ok(PeerMessage::Extended(message)) = message.to_peer_message() {
+                if message.payload.first() == Some(&id) {
+                    return Ok(message.payload[1..].to_vec());
+                }
+            }
+        }
+    }
+}
+
+/// The `x.pe` peer hints of a magnet link that are IP socket addresses.
+pub fn hinted_peers(magnet: &Magnet) -> Vec<Peer> {
+    magnet
+        .peers
+        .iter()
+        .filter_map(|addr| addr.parse().ok())
+        .map(|addr| Peer {
+            addr,
+            peer_id: None,
+        })
+        .collect()
+}
+
+/// Peers for a magnet link: its `x.pe` hints followed by whatever the
+/// first responsive tracker returns.
+pub fn magnet_peers(magnet: &Magnet) -> anyhow::Result<Vec<Peer>> {
+    let mut peers = hinted_peers(magnet);
+    let mut trackers = TrackerTiers::new(vec![magnet.trackers.clone()]);
+    if !trackers.is_empty() {
+        // the size is unknown until the metadata arrives
+        match trackers.try_each(|url| tracker::announce(url, &magnet.info_hash, 1)) {
+            Ok((_, response)) => peers.extend(response.peers),
+            Err(error) if !peers.is_empty() => eprintln!("{:#}", error),
+            Err(error) => return Err(error),
+        }
+    }
+    if peers.is_empty() {
+        bail!("the magnet link has no trackers or peers");
+    }
+    Ok(peers)
+}
+
+/// Fetch the info dictionary from the first peer that delivers it and
+/// build a `Torrent` that announces to the magnet link's trackers.
+pub fn fetch_torrent(magnet: &Magnet) -> anyhow::Result<Torrent> {
+    let mut last_error = None;
+    for peer in magnet_peers(magnet)? {
+        let result = MetadataPeer::connect(&peer.to_string(), magnet.info_hash)
+            .and_then(|mut connection| connection.fetch_metadata());
+        match result {
+            Ok(info) => {
+                let trackers = TrackerTiers::new(vec![magnet.trackers.clone()]);
+                return Torrent::from_info_bytes(&info, trackers);
+            }
+            Err(error) => {
+                eprintln!("peer {} failed: {:#}", peer, error);
+                last_error = Some(error);
+            }
+        }
+    }
+    Err(last_error
+        .context("no peers")?
+        .context("no peer delivered the metadata"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extension_handshake() {
+        let value = b"d1:md11:ut_metadatai3e6:ut_pexi2ee13:metadata_sizei132e1:v4:testee";
+        let handshake: ExtensionHandshake = bencode::from_bytes(value).unwrap();
+        assert_eq!(handshake.ut_metadata_id(), Some(3));
+        assert_eq!(handshake.metadata_size, Some(132));
+
+        assert_eq!(
+            bencode::to_bytes(&ExtensionHandshake::local()).unwrap(),
+            b"d1:md11:ut_metadatai1eee"
+        );
+
+        let disabled: ExtensionHandshake =
+            bencode::from_bytes(b"d1:md11:ut_metadatai0eee").unwrap();
+        assert_eq!(disabled.ut_metadata_id(), None);
+    }
+
+    #[test]
+    fn metadata_message_with_trailing_data() {
+        let request = bencode::to_bytes(&MetadataMessage::request(0)).unwrap();
+        assert_eq!(request, b"d8:msg_typei0e5:piecei0ee");
+
+        let payload = b"d8:msg_typei1e5:piecei0e10:total_sizei5ee12345";
+        let (message, data) = MetadataMessage::parse(payload).unwrap();
+        assert_eq!(message.total_size, Some(5));
+        assert_eq!(data, b"12345");
+    }
+
+    #[test]
+    fn assembles_and_verifies_metadata() {
+        let metadata: Vec<u8> = (0..METADATA_PIECE_SIZE + 10).map(|i| i as u8).collect();
+        let info_hash = InfoHash(Sha1::digest(&metadata).into());
+
+        let mut assembler = MetadataAssembler::new(info_hash, metadata.len()).unwrap();
+        assert_eq!(assembler.missing(), [0, 1]);
+        assert!(assembler.add(1, &metadata[..10]).is_ok());
+        assert!(assembler.add(0, &metadata[..10]).is_err());
+        assert!(assembler.add(2, &metadata[..10]).is_err());
+        assembler.add(0, &metadata[..METADATA_PIECE_SIZE]).unwrap();
+        assert_eq!(assembler.finish().unwrap(), metadata);
+
+        // same sizes, wrong data
+        let mut assembler = MetadataAssembler::new(info_hash, metadata.len()).unwrap();
+        assembler
+            .add(0, &metadata[10..METADATA_PIECE_SIZE + 10])
+            .unwrap();
+        assembler.add(1, &metadata[..10]).unwrap();
+        assert!(assembler.finish().is_err());
+
+        assert!(MetadataAssembler::new(info_hash, 0).is_err());
+
+        let v2_hash = InfoHashV2(Sha256::digest(&metadata)).truncated();
+        let mut assembler = MetadataAssembler::new(v2_hash, metadata.len()).unwrap();
+        assembler.add(0, &metadata[..METADATA_PIECE_SIZE]).unwrap();
+        assembler.add(1, &metadata[METADATA_PIECE_SIZE..]).unwrap();
+        assert_eq!(assembler.finish().unwrap(), metadata);
+    }
+
+    #[test]
+    fn fetch_from_peer() {
+        use std::net::TcpListener;
+
+        let info = b"d6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let info_hash = InfoHash(Sha1::digest(info).into());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let seeder = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut handshake = [0u8; 68];
+            stream.read_exact(&mut handshake).unwrap();
+            assert!(handshake.to_vec().to_handshake().supports_extensions());
+            stream.write_all(&handshake).unwrap();
+
+            let message = read_message(&mut stream).unwrap();
+            assert_eq!(message[5], HANDSHAKE_ID);
+            let ours = format!("d1:md11:ut_metadatai2ee13:metadata_sizei{}ee", info.len());
+            stream
+                .write_all(&PeerMessage::extended(HANDSHAKE_ID, ours.as_bytes()).to_message())
+                .unwrap();
+
+            let message = read_message(&mut stream).unwrap();
+            assert_eq!(message[5], 2);
+            assert_eq!(&message[6..], b"d8:msg_typei0e5:piecei0ee");
+            let mut data =
+                format!("d8:msg_typei1e5:piecei0e10:total_sizei{}ee", info.len()).into_bytes();
+            data.extend_from_slice(info);
+            stream
+                .write_all(&PeerMessage::extended(LOCAL_UT_METADATA_ID, &data).to_message())
+                .unwrap();
+        });
+
+        let mut peer = MetadataPeer::connect(&addr, info_hash).unwrap();
+        assert_eq!(peer.extensions.ut_metadata_id(), Some(2));
+        let metadata = peer.fetch_metadata().unwrap();
+        assert_eq!(metadata, info);
+        seeder.join().unwrap();
+
+        let torrent = Torrent::from_info_bytes(&metadata, TrackerTiers::default()).unwrap();
+        assert_eq!(torrent.info_hash, info_hash);
+    }
+}
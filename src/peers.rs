@@ -0,0 +1,66 @@
+//! # Peers
+//!
+//! sources of peers besides a torrent's own trackers, and which of them a
+//! torrent may use
+//!
+
+use crate::torrent::InfoHash;
+use crate::tracker::Peer;
+use std::fmt;
+
+/// Where a peer list comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    /// The torrent's own trackers.
+    Tracker,
+    /// The distributed hash table (BEP 5).
+    Dht,
+    /// Peer exchange between connected peers (BEP 11).
+    Pex,
+    /// Local service discovery (BEP 14).
+    Lsd,
+    /// Addresses given up front, e.g. `x.pe` in a magnet link.
+    Hint,
+}
+
+impl PeerSource {
+    /// Private torrents (BEP 27) may only get peers from their trackers.
+    pub fn allowed_for_private(self) -> bool {
+        self == PeerSource::Tracker
+    }
+}
+
+impl fmt::Display for PeerSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            PeerSource::Tracker => "tracker",
+            PeerSource::Dht => "DHT",
+            PeerSource::Pex => "PEX",
+            PeerSource::Lsd => "LSD",
+            PeerSource::Hint => "peer hints",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Something that can be asked for peers of a torrent.
+pub trait PeerProvider {
+    fn source(&self) -> PeerSource;
+    fn peers(&mut self, info_hash: &InfoHash) -> anyhow::Result<Vec<Peer>>;
+}
+
+/// A fixed list of peers.
+#[derive(Debug, Clone, Default)]
+pub struct PeerHints {
+    pub peers: Vec<Peer>,
+}
+
+impl PeerProvider for PeerHints {
+    fn source(&self) -> PeerSource {
+        PeerSource::Hint
+    }
+
+    fn peers(&mut self, _info_hash: &InfoHash) -> anyhow::Result<Vec<Peer>> {
+        Ok(self.peers.clone())
+    }
+}
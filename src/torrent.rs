@@ -1,16 +1,27 @@
-use crate::bencode::Bencode;
-use anyhow::Context;
-use serde_json::Value;
+use crate::bencode::{self, BValue, BencodeError, DecodeOptions, Decoder, Limits};
+use crate::peers::{PeerProvider, PeerSource};
+use crate::sha256::Sha256;
+use crate::tracker::{self, AnnounceResponse, Peer, TrackerTiers};
+use crate::v2::{self, InfoHashV2, MetaVersion, V2File};
+use anyhow::{bail, Context};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
 use std::fmt;
+use std::fs;
 use std::io::{Read, Write};
 use std::net::TcpStream;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::thread::sleep;
 use std::time::Duration;
 
 pub struct HandShake {
     // length: u8,
     // kind: [u8; 19],
-    // reserved: [u8; 8],
+    pub reserved: [u8; 8],
     pub info_hash: InfoHash,
     pub peer_id: Vec<u8>,
 }
@@ -19,11 +30,19 @@ impl HandShake {
         let mut vec: Vec<u8> = Vec::new();
         vec.push(19u8);
         vec.extend_from_slice(b"BitTorrent protocol");
-        vec.extend_from_slice(&[0u8; 8]);
-        vec.extend_from_slice(&self.info_hash.to_hex());
+        vec.extend_from_slice(&self.reserved);
+        vec.extend_from_slice(self.info_hash.as_bytes());
         vec.extend_from_slice(&self.peer_id); // peer id
         vec
     }
+    /// Advertise the extension protocol (BEP 10).
+    pub fn with_extensions(mut self) -> Self {
+        self.reserved[5] |= EXTENSION_BIT;
+        self
+    }
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & EXTENSION_BIT != 0
+    }
     pub fn peer_id_as_str(&self) -> String {
         self.peer_id
             .iter()
@@ -32,6 +51,9 @@ impl HandShake {
     }
 }
 
+/// Reserved bit 20 from the right, in byte 5, announces BEP 10 support.
+const EXTENSION_BIT: u8 = 0x10;
+
 pub trait ToHandShake {
     fn to_handshake(&self) -> HandShake;
 }
@@ -39,9 +61,8 @@ pub trait ToHandShake {
 impl ToHandShake for Torrent {
     fn to_handshake(&self) -> HandShake {
         HandShake {
-            info_hash: InfoHash {
-                val: (&self.info_hash.val[..]).into(),
-            },
+            reserved: [0u8; 8],
+            info_hash: self.info_hash,
             peer_id: (&self.peer_id[..]).into(),
         }
     }
@@ -49,107 +70,715 @@ impl ToHandShake for Torrent {
 
 impl ToHandShake for Vec<u8> {
     fn to_handshake(&self) -> HandShake {
+        let mut info_hash = [0u8; 20];
+        info_hash.copy_from_slice(&self[self.len() - 40..self.len() - 20]);
+        let mut reserved = [0u8; 8];
+        reserved.copy_from_slice(&self[self.len() - 48..self.len() - 40]);
         HandShake {
-            info_hash: InfoHash {
-                val: hex::encode(&self[self.len() - 40..self.len() - 20]),
-            },
+            reserved,
+            info_hash: InfoHash(info_hash),
             peer_id: self[self.len() - 20..].to_vec(),
         }
     }
 }
 
-pub struct InfoHash {
-    val: String,
+/// The SHA-1 of a torrent's bencoded `info` dictionary.
+///
+/// Parses from 40 hex digits or 32 base32 characters (the two forms found
+/// in magnet links) and displays as lowercase hex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InfoHash(pub [u8; 20]);
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ParseInfoHashError {
+    #[error("info hash must be 40 hex or 32 base32 characters, got {0}")]
+    Length(usize),
+    #[error("invalid hex info hash")]
+    Hex,
+    #[error("invalid base32 info hash")]
+    Base32,
 }
 
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
 impl fmt::Display for InfoHash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.val)
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl std::str::FromStr for InfoHash {
+    type Err = ParseInfoHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0u8; 20];
+        match s.len() {
+            40 => hex::decode_to_slice(s, &mut bytes).map_err(|_| ParseInfoHashError::Hex)?,
+            32 => {
+                // 32 characters of 5 bits each are exactly 160 bits
+                let mut acc = 0u64;
+                let mut bits = 0;
+                let mut out = 0;
+                for c in s.bytes() {
+                    let value = BASE32_ALPHABET
+                        .iter()
+                        .position(|&a| a == c.to_ascii_uppercase())
+                        .ok_or(ParseInfoHashError::Base32)?;
+                    acc = (acc << 5) | value as u64;
+                    bits += 5;
+                    if bits >= 8 {
+                        bits -= 8;
+                        bytes[out] = (acc >> bits) as u8;
+                        out += 1;
+                    }
+                }
+            }
+            len => return Err(ParseInfoHashError::Length(len)),
+        }
+        Ok(InfoHash(bytes))
     }
 }
 
 impl InfoHash {
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+    /// Percent-encode the raw bytes for a tracker query string, leaving
+    /// RFC 3986 unreserved characters as they are.
     pub fn to_url(&self) -> String {
-        self.val
-            .chars()
-            .collect::<Vec<char>>()
-            .chunks(2)
-            // .inspect(|arr| println!("%{}{}", arr[0], arr[1]))
-            .map(|arr| format!("%{}{}", arr[0], arr[1]).to_string())
-            .collect::<String>()
+        self.0
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                    (b as char).to_string()
+                } else {
+                    format!("%{:02X}", b)
+                }
+            })
+            .collect()
     }
-    pub fn to_hex(&self) -> Vec<u8> {
-        hex::decode(&(self.val)).expect("to be hexadecimal")
+    pub fn to_base32(&self) -> String {
+        let mut encoded = String::with_capacity(32);
+        let mut acc = 0u64;
+        let mut bits = 0;
+        for &b in &self.0 {
+            acc = (acc << 8) | b as u64;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                encoded.push(BASE32_ALPHABET[(acc >> bits) as usize & 31] as char);
+            }
+        }
+        encoded
+    }
+}
+
+fn metainfo_options() -> DecodeOptions {
+    DecodeOptions {
+        limits: Limits::torrent_file(),
+        ..DecodeOptions::default()
     }
 }
 
+/// Decode the raw contents of a `.torrent` file, with the looser limits
+/// appropriate for local files.
+pub fn decode_metainfo(bytes: &[u8]) -> Result<BValue, BencodeError> {
+    Decoder::with_options(bytes, metainfo_options()).decode_value()
+}
+
+/// [`decode_metainfo`], also finding the byte range of `info` in the same
+/// pass.
+pub fn decode_metainfo_with_info(bytes: &[u8]) -> anyhow::Result<(BValue, Range<usize>)> {
+    let (value, info_span) = Decoder::with_options(bytes, metainfo_options())
+        .decode_with_span_of("info")
+        .context("decode torrent")?;
+    let info_span = info_span.context("the torrent has no `info` dictionary")?;
+    Ok((value, info_span))
+}
+
+/// The metainfo dictionary of a `.torrent` file.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MetaInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce: Option<String>,
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(rename = "created by", skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    /// Seconds since the Unix epoch.
+    #[serde(rename = "creation date", skip_serializing_if = "Option::is_none")]
+    pub creation_date: Option<i64>,
+    /// Web seeds (BEP 19); a single URL string is also accepted.
+    #[serde(rename = "url-list", skip_serializing_if = "Option::is_none")]
+    pub url_list: Option<OneOrMany>,
+    /// Character set of the strings in `info`, e.g. `UTF-8`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    /// HTTP seeds (BEP 17).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub httpseeds: Option<OneOrMany>,
+    pub info: Info,
+    /// v2 piece hashes of every file longer than a piece, keyed by the
+    /// file's pieces root.
+    #[serde(rename = "piece layers", skip_serializing_if = "Option::is_none")]
+    pub piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
+    /// Keys not modelled above, kept for writing the torrent back. Keys
+    /// are bytes: bencode does not require them to be UTF-8.
+    #[serde(flatten)]
+    pub extra: BTreeMap<ByteBuf, BValue>,
+}
+
+/// Checks whether a value reads as the type of one metainfo field.
+type TypeCheck = fn(&BValue) -> bool;
+
+/// Whether `value` reads as a `T`.
+fn fits<T: DeserializeOwned>(value: &BValue) -> bool {
+    bencode::from_value::<T>(value).is_ok()
+}
+
+/// Optional top-level keys, with a check for the type [`MetaInfo`] reads
+/// them as. A value of another type is kept in `extra` rather than failing
+/// the whole torrent.
+const LENIENT_KEYS: &[(&str, TypeCheck)] = &[
+    ("announce", fits::<String>),
+    ("announce-list", fits::<Vec<Vec<String>>>),
+    ("comment", fits::<String>),
+    ("created by", fits::<String>),
+    ("creation date", fits::<i64>),
+    ("url-list", fits::<OneOrMany>),
+    ("encoding", fits::<String>),
+    ("httpseeds", fits::<OneOrMany>),
+];
+
+impl MetaInfo {
+    /// Read the metainfo from a decoded `.torrent` file. Optional keys of
+    /// the wrong type, such as a string `creation date` or a flat
+    /// `announce-list`, end up in `extra` and are described in the returned
+    /// warnings.
+    pub fn from_value(value: &BValue) -> anyhow::Result<(MetaInfo, Vec<String>)> {
+        let BValue::Dict(dict) = value else {
+            bail!("the torrent is not a dictionary");
+        };
+        let mut dict = dict.clone();
+        let mut misfits = Vec::new();
+        for (key, fits) in LENIENT_KEYS {
+            if dict.get(key.as_bytes()).is_some_and(|value| !fits(value)) {
+                let value = dict.remove(key.as_bytes()).expect("key is present");
+                misfits.push((ByteBuf::from(key.as_bytes()), value));
+            }
+        }
+        let mut meta: MetaInfo = bencode::from_value(&BValue::Dict(dict))?;
+        let warnings = misfits
+            .iter()
+            .map(|(key, _)| format!("ignoring `{}`, which has an unexpected type", lossy(key)))
+            .collect();
+        meta.extra.extend(misfits);
+        Ok((meta, warnings))
+    }
+}
+
+/// A list of URLs that may also be given as a single string; the form read
+/// is kept so the value is written back the same way.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    pub fn urls(&self) -> &[String] {
+        match self {
+            OneOrMany::One(url) => std::slice::from_ref(url),
+            OneOrMany::Many(urls) => urls,
+        }
+    }
+}
+
+/// The `info` dictionary. Exactly one of `length` (single-file) and `files`
+/// (multi-file) is present.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Info {
+    /// Kept as bytes: names are not guaranteed to be UTF-8.
+    pub name: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub md5sum: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileEntry>>,
+    #[serde(rename = "piece length")]
+    pub piece_length: usize,
+    /// SHA-1 piece hashes; absent in v2-only torrents.
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+    pub pieces: Option<Vec<u8>>,
+    /// 2 for v2 and hybrid torrents (BEP 52).
+    #[serde(rename = "meta version", skip_serializing_if = "Option::is_none")]
+    pub meta_version: Option<i64>,
+    #[serde(rename = "file tree", skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<BValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private: Option<i64>,
+    /// Distinguishes otherwise identical torrents, e.g. across trackers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Keys not modelled above, kept for writing the info back.
+    #[serde(flatten)]
+    pub extra: BTreeMap<ByteBuf, BValue>,
+}
+
+/// One file of a multi-file torrent.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FileEntry {
+    pub length: usize,
+    /// Path components below the torrent's directory, as raw bytes.
+    pub path: Vec<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub md5sum: Option<String>,
+    /// BEP 47 file attributes, e.g. `p` for padding.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
+
+impl FileEntry {
+    /// A BEP 47 padding file, which holds zeros and is not written to disk.
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains('p'))
+    }
+
+    /// Path components decoded as UTF-8, with invalid bytes replaced.
+    pub fn lossy_path(&self) -> Vec<String> {
+        self.path.iter().map(|c| lossy(c)).collect()
+    }
+
+    /// The path as a relative `PathBuf`, refusing components that could
+    /// escape the download directory.
+    pub fn relative_path(&self) -> anyhow::Result<PathBuf> {
+        relative_path(&self.lossy_path())
+    }
+}
+
+/// Decode a name or path component for display and file system use.
+pub(crate) fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// Join path components from a torrent, refusing any that could escape the
+/// download directory.
+pub(crate) fn relative_path(path: &[String]) -> anyhow::Result<PathBuf> {
+    let unsafe_component =
+        |c: &String| c.is_empty() || c == "." || c == ".." || c.contains(['/', '\\']);
+    if path.is_empty() || path.iter().any(unsafe_component) {
+        bail!("unsafe file path {:?}", path);
+    }
+    Ok(path.iter().collect())
+}
+
+/// How the torrent's data maps onto files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Layout {
+    SingleFile {
+        length: usize,
+        md5sum: Option<String>,
+    },
+    MultiFile {
+        files: Vec<FileEntry>,
+    },
+}
+
+impl Layout {
+    fn from_info(info: &Info) -> anyhow::Result<Layout> {
+        match (info.length, &info.files) {
+            (Some(length), None) => Ok(Layout::SingleFile {
+                length,
+                md5sum: info.md5sum.clone(),
+            }),
+            (None, Some(files)) => Ok(Layout::MultiFile {
+                files: files.clone(),
+            }),
+            (Some(_), Some(_)) => bail!("info has both `length` and `files`"),
+            (None, None) => bail!("info has neither `length` nor `files`"),
+        }
+    }
+
+    /// The layout of a v2-only torrent: a single file when the tree holds
+    /// one file named like the torrent.
+    fn from_v2_files(name: &str, files: &[V2File]) -> Layout {
+        match files {
+            [file] if file.path == [name] => Layout::SingleFile {
+                length: file.length,
+                md5sum: None,
+            },
+            files => Layout::MultiFile {
+                files: files
+                    .iter()
+                    .map(|file| FileEntry {
+                        length: file.length,
+                        path: file
+                            .path
+                            .iter()
+                            .map(|c| ByteBuf::from(c.as_bytes()))
+                            .collect(),
+                        md5sum: None,
+                        attr: None,
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    /// Sum of all file lengths, padding included, as the pieces cover them.
+    pub fn total_length(&self) -> usize {
+        match self {
+            Layout::SingleFile { length, .. } => *length,
+            Layout::MultiFile { files } => files.iter().map(|file| file.length).sum(),
+        }
+    }
+
+    /// Sum of the lengths of the files actually stored, leaving out BEP 47
+    /// padding.
+    pub fn data_length(&self) -> usize {
+        match self {
+            Layout::SingleFile { length, .. } => *length,
+            Layout::MultiFile { files } => files
+                .iter()
+                .filter(|file| !file.is_padding())
+                .map(|file| file.length)
+                .sum(),
+        }
+    }
+
+    /// Where each file lives when the torrent is stored at `root`, with its
+    /// length, in piece order. Padding files have no path: their zeros only
+    /// exist in the piece data.
+    pub fn file_paths(&self, root: &Path) -> anyhow::Result<Vec<(Option<PathBuf>, usize)>> {
+        match self {
+            Layout::SingleFile { length, .. } => Ok(vec![(Some(root.to_path_buf()), *length)]),
+            Layout::MultiFile { files } => files
+                .iter()
+                .map(|file| match file.is_padding() {
+                    true => Ok((None, file.length)),
+                    false => Ok((Some(root.join(file.relative_path()?)), file.length)),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// How often a piece is requested again after failing its hash check.
+const MAX_PIECE_ATTEMPTS: usize = 3;
+
+fn split_piece_hashes(pieces: &[u8]) -> anyhow::Result<Vec<[u8; 20]>> {
+    if !pieces.len().is_multiple_of(20) {
+        bail!("pieces length {} is not a multiple of 20", pieces.len());
+    }
+    Ok(pieces
+        .chunks_exact(20)
+        .map(|chunk| chunk.try_into().expect("chunk of 20 bytes"))
+        .collect())
+}
+
 pub struct Torrent {
+    /// The first tracker, for display.
     pub url: String,
+    /// Every tracker, shuffled within tiers.
+    pub trackers: TrackerTiers,
+    /// Web seed URLs from `url-list`.
+    pub web_seeds: Vec<String>,
+    /// Total length of all files, padding included; see
+    /// [`Layout::data_length`] for the bytes actually stored.
     pub length: usize,
     pub info_hash: InfoHash,
     pub piece_length: usize,
-    pub piece_hashes: String,
+    pub piece_hashes: Vec<[u8; 20]>,
     pub peer_id: Vec<u8>,
+    /// File name for single-file torrents, directory name otherwise.
+    pub name: String,
+    pub layout: Layout,
+    /// Which hash trees the torrent carries.
+    pub version: MetaVersion,
+    /// SHA-256 of `info` for v2 and hybrid torrents. For v2-only torrents
+    /// `info_hash` is its truncated form.
+    pub info_hash_v2: Option<InfoHashV2>,
+    /// The v2 `file tree` with piece layers attached; empty for v1.
+    pub v2_files: Vec<V2File>,
+    /// Everything read from the file, including optional and unknown keys.
+    pub meta: MetaInfo,
+    /// Problems tolerated while reading, e.g. optional keys of the wrong
+    /// type.
+    pub warnings: Vec<String>,
+    /// Peer sources besides the trackers, consulted by [`Torrent::find_peers`]
+    /// when [`Torrent::allows_peer_source`] permits.
+    pub peer_sources: Vec<Box<dyn PeerProvider>>,
 }
 
 impl Torrent {
-    pub fn new(decoded_value: &Value) -> anyhow::Result<Torrent> {
-        let map = decoded_value.as_object().context("read map object")?;
+    /// Parse a `.torrent` file. The info hash is the SHA-1 of the `info`
+    /// dictionary exactly as encoded in `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Torrent> {
+        let (value, info_span) = decode_metainfo_with_info(bytes)?;
+        Torrent::from_decoded(&value, &bytes[info_span])
+    }
+
+    /// Build a torrent from a `.torrent` file already decoded by
+    /// [`decode_metainfo_with_info`], with the raw bytes of its `info`.
+    pub fn from_decoded(value: &BValue, info_bytes: &[u8]) -> anyhow::Result<Torrent> {
+        let (meta, warnings) = MetaInfo::from_value(value).context("read metainfo")?;
+        let trackers =
+            TrackerTiers::from_metainfo(meta.announce.as_deref(), meta.announce_list.as_deref());
+        let mut torrent = Torrent::from_parts(meta, info_bytes, trackers)?;
+        torrent.warnings = warnings;
+        Ok(torrent)
+    }
+
+    /// Build a torrent from a bare bencoded `info` dictionary, as fetched
+    /// from peers for a magnet link.
+    pub fn from_info_bytes(info_bytes: &[u8], trackers: TrackerTiers) -> anyhow::Result<Torrent> {
+        let value = Decoder::with_options(info_bytes, metainfo_options())
+            .decode_all()
+            .context("decode info")?;
+        let info: Info = bencode::from_value(&value).context("read info")?;
+        let meta = MetaInfo {
+            announce: trackers.first().map(str::to_string),
+            announce_list: (trackers.urls().nth(1).is_some()).then(|| trackers.tiers().to_vec()),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            url_list: None,
+            encoding: None,
+            httpseeds: None,
+            info,
+            piece_layers: None,
+            extra: BTreeMap::new(),
+        };
+        Torrent::from_parts(meta, info_bytes, trackers)
+    }
+
+    fn from_parts(
+        meta: MetaInfo,
+        info_bytes: &[u8],
+        mut trackers: TrackerTiers,
+    ) -> anyhow::Result<Torrent> {
+        let info = &meta.info;
+        let version = match (&info.pieces, info.meta_version) {
+            (Some(_), None) => MetaVersion::V1,
+            (Some(_), Some(2)) => MetaVersion::Hybrid,
+            (None, Some(2)) => MetaVersion::V2,
+            (_, Some(other)) => bail!("unsupported meta version {}", other),
+            (None, None) => bail!("info has no `pieces`"),
+        };
+        if info.piece_length == 0 {
+            bail!("piece length is zero");
+        }
+
+        let mut v2_files = Vec::new();
+        if version.has_v2() {
+            v2::check_piece_length(info.piece_length)?;
+            let tree = info
+                .file_tree
+                .as_ref()
+                .context("v2 info has no `file tree`")?;
+            v2_files = v2::parse_file_tree(tree)?;
+            // metadata fetched from peers comes without piece layers
+            if let Some(layers) = &meta.piece_layers {
+                v2::attach_piece_layers(&mut v2_files, layers, info.piece_length)?;
+            }
+        }
+
+        let layout = if version.has_v1() {
+            Layout::from_info(info)?
+        } else {
+            Layout::from_v2_files(&lossy(&info.name), &v2_files)
+        };
+        let piece_hashes = split_piece_hashes(info.pieces.as_deref().unwrap_or_default())?;
+        let length = layout.total_length();
+        if version.has_v1() && piece_hashes.len() != length.div_ceil(info.piece_length) {
+            bail!(
+                "{} piece hashes do not cover {} bytes in pieces of {}",
+                piece_hashes.len(),
+                length,
+                info.piece_length
+            );
+        }
+        let info_hash_v2 = version
+            .has_v2()
+            .then(|| InfoHashV2(Sha256::digest(info_bytes)));
+        let info_hash = match info_hash_v2 {
+            Some(v2_hash) if !version.has_v1() => v2_hash.truncated(),
+            _ => InfoHash(Sha1::digest(info_bytes).into()),
+        };
+        trackers.shuffle();
 
         Ok(Torrent {
-            url: map["announce"].as_str().context("read url")?.to_string(),
-            length: map["info"]["length"].as_i64().context("read length")? as usize,
-            info_hash: InfoHash {
-                val: map["info hash"]
-                    .as_str()
-                    .context("read info hash")?
-                    .to_string(),
-            },
-            piece_length: map["info"]["piece length"]
-                .as_i64()
-                .context("read piece length")? as usize,
-            piece_hashes: map["info"]["pieces"]
-                .as_str()
-                .context("read peiece hashes")?
-                .to_string(),
+            url: trackers.first().unwrap_or_default().to_string(),
+            trackers,
+            // `url-list` is often an empty string when there are no seeds
+            web_seeds: meta
+                .url_list
+                .iter()
+                .flat_map(OneOrMany::urls)
+                .filter(|url| !url.is_empty())
+                .cloned()
+                .collect(),
+            length,
+            info_hash,
+            info_hash_v2,
+            version,
+            v2_files,
+            piece_length: info.piece_length,
+            piece_hashes,
             peer_id: b"00112233445566778899".to_vec(),
+            name: lossy(&info.name),
+            layout,
+            meta,
+            warnings: Vec::new(),
+            peer_sources: Vec::new(),
         })
     }
-    pub fn download(&self, piece_index: usize) -> anyhow::Result<Vec<u8>> {
-        eprintln!(
-            "total length: {}, piece length: {}",
-            self.length, self.piece_length
-        );
-        eprintln!("info_hash: {}, peer_id: {:?}", self.info_hash, self.peer_id);
-        eprintln!("|||||||||||||| Query Peer |||||||||||||||||");
-        let left = self.piece_length.to_string();
-        let query_params = vec![
-            ("uploaded", "0"),
-            ("downloaded", "0"),
-            ("compact", "1"),
-            ("left", &left),
-            ("peer_id", "00112233445566778899"),
-            ("port", "6881"),
-        ];
 
-        let url_with_query = format!(
-            "{}?{}&info_hash={}",
-            self.url,
-            serde_urlencoded::to_string(query_params)?,
-            self.info_hash.to_url()
-        );
+    /// The whole metainfo as JSON, with piece hashes as a list of hex
+    /// strings and the info hash added as `info hash`.
+    pub fn to_json(&self) -> anyhow::Result<serde_json::Value> {
+        let mut json = bencode::to_value(&self.meta)?.to_json();
+        let hashes = self
+            .piece_hashes
+            .iter()
+            .map(|h| hex::encode(h).into())
+            .collect();
+        if self.version.has_v1() {
+            json["info"]["pieces"] = serde_json::Value::Array(hashes);
+        }
+        json["info hash"] = self.info_hash.to_string().into();
+        if let Some(hash) = &self.info_hash_v2 {
+            json["info hash v2"] = hash.to_string().into();
+        }
+        Ok(json)
+    }
 
-        let resp = reqwest::blocking::get(url_with_query)?;
+    /// Write downloaded `data` (all pieces, in order) to disk. A single-file
+    /// torrent is written to `output`; a multi-file torrent creates its
+    /// directory tree with `output` as the top directory.
+    pub fn write_files(&self, data: &[u8], output: &Path) -> anyhow::Result<()> {
+        if data.len() != self.length {
+            bail!(
+                "have {} bytes of data but the torrent holds {}",
+                data.len(),
+                self.length
+            );
+        }
+        let mut offset = 0;
+        for (path, length) in self.layout.file_paths(output)? {
+            let Some(path) = path else {
+                offset += length;
+                continue;
+            };
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("create {}", parent.display()))?;
+            }
+            fs::write(&path, &data[offset..offset + length])
+                .with_context(|| format!("write {}", path.display()))?;
+            offset += length;
+        }
+        Ok(())
+    }
+    /// Whether `info.private` is set (BEP 27).
+    pub fn is_private(&self) -> bool {
+        self.meta.info.private == Some(1)
+    }
+
+    /// Private torrents only get peers from their own trackers.
+    pub fn allows_peer_source(&self, source: PeerSource) -> bool {
+        !self.is_private() || source.allowed_for_private()
+    }
+
+    pub fn add_peer_source(&mut self, provider: Box<dyn PeerProvider>) {
+        self.peer_sources.push(provider);
+    }
 
-        let body = resp.bytes()?;
-        let decoded = body.to_vec().bdecode();
+    /// Peers from the first tracker that answers and from every other peer
+    /// source the torrent allows, without duplicates.
+    pub fn find_peers(&mut self) -> anyhow::Result<Vec<Peer>> {
+        let mut responses = Vec::new();
+        let mut last_error = None;
+        if !self.trackers.is_empty() {
+            match self.announce() {
+                Ok((url, response)) => {
+                    eprintln!("tracker: {}", url);
+                    responses.push(response);
+                }
+                Err(error) => last_error = Some(error),
+            }
+        }
+        let private = self.is_private();
+        for provider in &mut self.peer_sources {
+            let source = provider.source();
+            if private && !source.allowed_for_private() {
+                eprintln!("skipping {} for a private torrent", source);
+                continue;
+            }
+            match provider.peers(&self.info_hash) {
+                Ok(peers) => responses.push(AnnounceResponse {
+                    peers,
+                    ..AnnounceResponse::default()
+                }),
+                Err(error) => last_error = Some(error.context(format!("{} failed", source))),
+            }
+        }
 
-        let mut ip_addresses: Vec<String> = Vec::new();
-        if let Value::Array(vec) = &decoded["peers"] {
-            vec.iter().for_each(|map| {
-                ip_addresses.push(format!("{}:{}", map["ip"].as_str().unwrap(), map["port"]))
+        let peers = tracker::merge_peers(&responses);
+        if peers.is_empty() {
+            return Err(match last_error {
+                Some(error) => error.context("no peers found"),
+                None => anyhow::anyhow!("no peers found"),
             });
         }
+        Ok(peers)
+    }
+
+    /// Announce to the first tracker that answers, trying tiers in order.
+    pub fn announce(&mut self) -> anyhow::Result<(String, AnnounceResponse)> {
+        let info_hash = self.info_hash;
+        let left = self.layout.data_length();
+        self.trackers
+            .try_each(|url| tracker::announce(url, &info_hash, left))
+    }
+
+    /// Announce to every tracker, returning each one's outcome.
+    pub fn announce_all(&self) -> Vec<(String, anyhow::Result<AnnounceResponse>)> {
+        self.trackers
+            .urls()
+            .map(|url| {
+                (
+                    url.to_string(),
+                    tracker::announce(url, &self.info_hash, self.layout.data_length()),
+                )
+            })
+            .collect()
+    }
+
+    pub fn download(&mut self, piece_index: usize) -> anyhow::Result<Vec<u8>> {
+        self.check_v1_download()?;
+        if piece_index >= self.piece_count() {
+            bail!(
+                "piece {} out of range, the torrent has {} pieces",
+                piece_index,
+                self.piece_count()
+            );
+        }
+        eprintln!(
+            "total length: {}, piece length: {}",
+            self.length, self.piece_length
+        );
+        eprintln!("info_hash: {}, peer_id: {:?}", self.info_hash, self.peer_id);
+        eprintln!("|||||||||||||| Query Peer |||||||||||||||||");
+        let ip_addresses: Vec<String> = self.find_peers()?.iter().map(Peer::to_string).collect();
 
         // handshake
         eprintln!("|||||||||||||| HandShake ||||||||||||||||||");
@@ -193,6 +822,7 @@ impl Torrent {
             PeerMessage::Unchoke(_) => eprintln!("[Unchoke]"),
             PeerMessage::Request(_) => eprintln!("[Request]"),
             PeerMessage::Piece(_) => eprintln!("[Piece]"),
+            PeerMessage::Extended(_) => eprintln!("[Extended]"),
         }
         // println!("Peer message type: {:?}", peer_message);
 
@@ -216,76 +846,19 @@ impl Torrent {
             PeerMessage::Unchoke(_) => eprintln!("[Unchoke]"),
             PeerMessage::Request(_) => eprintln!("[Request]"),
             PeerMessage::Piece(_) => eprintln!("[Piece]"),
+            PeerMessage::Extended(_) => eprintln!("[Extended]"),
         }
 
         eprintln!("|||||||||||||| Request Data |||||||||||||||");
-        let mut piece_received = Vec::<u8>::new();
-        // request a piece
         if let PeerMessage::Unchoke(_) = peer_message {
-            // let message = PeerMessage::new(6, 0, 0, usize::pow(2, 14)).to_message();
-            let n_total = f64::ceil(self.length as f64 / (self.piece_length as f64)) as usize;
-            // let n_total = 1;
-            const BLOCK_CHUNK_SIZE: usize = usize::pow(2, 14);
-            // for index in 0..n_total {
-            eprintln!("=== Pieces: {} of {}", piece_index + 1, n_total);
-            let piece_length = {
-                if piece_index == n_total - 1 {
-                    self.length - piece_index * self.piece_length
-                } else {
-                    self.piece_length
-                }
-            };
-            let n_blocks = f64::ceil(piece_length as f64 / BLOCK_CHUNK_SIZE as f64) as usize;
-            for block_index in 0..n_blocks {
-                eprintln!("------ Blocks: {} of {}", block_index + 1, n_blocks);
-                let block_size = {
-                    if block_index == n_blocks - 1 {
-                        piece_length - BLOCK_CHUNK_SIZE * block_index
-                    } else {
-                        BLOCK_CHUNK_SIZE
-                    }
-                };
-                let message = PeerMessage::new(
-                    MessageType::Request,
-                    piece_index,
-                    block_index * BLOCK_CHUNK_SIZE,
-                    block_size,
-                )
-                .to_message();
-                // eprintln!("{:?} message sent", message);
-                stream.write_all(&message)?;
-
-                let mut message_recevied = vec![0u8; block_size + 13]; // initialize message buffer
-                                                                       // eprintln!("size: {}", message_recevied.len());
-                                                                       // let mut message_recevied = Vec::new(); // initialize message buffer
-                stream
-                    .read_exact(&mut message_recevied)
-                    .context("message read failed")?;
-                // eprintln!("the length of the received message is {message_size}");
-                // println!("{:?}", &message_recevied[..message_size]);
-                let peer_message = message_recevied
-                    .to_peer_message()
-                    .context("This is not a peer message")?;
-                match &peer_message {
-                    PeerMessage::Bitfield(_) => println!("[Bitfield]"),
-                    PeerMessage::Interested(_) => println!("[Interested]"),
-                    PeerMessage::Unchoke(_) => println!("[Unchoke]"),
-                    PeerMessage::Request(_) => println!("[Request]"),
-                    PeerMessage::Piece(_) => println!("[Piece]"),
-                }
-                if let PeerMessage::Piece(message) = peer_message {
-                    let block = &message.payload[8..];
-                    piece_received.extend_from_slice(block);
-                    eprintln!(
-                        "the current size of recieved pieces: {}",
-                        piece_received.len()
-                    );
-                }
-            }
+            eprintln!("=== Pieces: {} of {}", piece_index + 1, self.piece_count());
+            self.fetch_verified_piece(&mut stream, piece_index)
+        } else {
+            bail!("peer did not unchoke us");
         }
-        Ok(piece_received)
     }
-    pub fn download_all(&self) -> anyhow::Result<Vec<u8>> {
+    pub fn download_all(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.check_v1_download()?;
         eprintln!(
             "total length: {}, piece length: {}",
             self.length, self.piece_length
@@ -293,36 +866,7 @@ impl Torrent {
         eprintln!("info_hash: {}, peer_id: {:?}", self.info_hash, self.peer_id);
         // query peer
         eprintln!("|||||||||||||| Query Peer |||||||||||||||||");
-        let left = self.piece_length.to_string();
-        let query_params = vec![
-            ("uploaded", "0"),
-            ("downloaded", "0"),
-            ("compact", "1"),
-            ("left", &left),
-            ("peer_id", "00112233445566778899"),
-            ("port", "6881"),
-        ];
-
-        let url_with_query = format!(
-            "{}?{}&info_hash={}",
-            self.url,
-            serde_urlencoded::to_string(query_params)?,
-            self.info_hash.to_url()
-        );
-        // println!("{}", url_with_query);
-
-        let resp = reqwest::blocking::get(url_with_query)?;
-
-        let body = resp.bytes()?;
-        let decoded = body.to_vec().bdecode();
-        // println!("Body:\n{}", decoded.to_string());
-
-        let mut ip_addresses: Vec<String> = Vec::new();
-        if let Value::Array(vec) = &decoded["peers"] {
-            vec.iter().for_each(|map| {
-                ip_addresses.push(format!("{}:{}", map["ip"].as_str().unwrap(), map["port"]))
-            });
-        }
+        let ip_addresses: Vec<String> = self.find_peers()?.iter().map(Peer::to_string).collect();
 
         // handshake
         eprintln!("|||||||||||||| HandShake ||||||||||||||||||");
@@ -377,6 +921,7 @@ impl Torrent {
             PeerMessage::Unchoke(_) => eprintln!("[Unchoke]"),
             PeerMessage::Request(_) => eprintln!("[Request]"),
             PeerMessage::Piece(_) => eprintln!("[Piece]"),
+            PeerMessage::Extended(_) => eprintln!("[Extended]"),
         }
         // println!("Peer message type: {:?}", peer_message);
 
@@ -400,76 +945,182 @@ impl Torrent {
             PeerMessage::Unchoke(_) => eprintln!("[Unchoke]"),
             PeerMessage::Request(_) => eprintln!("[Request]"),
             PeerMessage::Piece(_) => eprintln!("[Piece]"),
+            PeerMessage::Extended(_) => eprintln!("[Extended]"),
         }
 
         eprintln!("|||||||||||||| Request Data |||||||||||||||");
         let mut piece_received = Vec::<u8>::new();
-        // request a piece
         if let PeerMessage::Unchoke(_) = peer_message {
-            // let message = PeerMessage::new(6, 0, 0, usize::pow(2, 14)).to_message();
-            let n_total = f64::ceil(self.length as f64 / (self.piece_length as f64)) as usize;
-            // let n_total = 1;
-            const BLOCK_CHUNK_SIZE: usize = usize::pow(2, 14);
-            for piece_index in 0..n_total {
-                eprintln!("=== Pieces: {} of {}", piece_index + 1, n_total);
-                let piece_length = {
-                    if piece_index == n_total - 1 {
-                        self.length - piece_index * self.piece_length
-                    } else {
-                        self.piece_length
-                    }
-                };
-                let n_blocks = f64::ceil(piece_length as f64 / BLOCK_CHUNK_SIZE as f64) as usize;
-                for block_index in 0..n_blocks {
-                    let block_size = {
-                        if block_index == n_blocks - 1 {
-                            piece_length - BLOCK_CHUNK_SIZE * block_index
-                        } else {
-                            BLOCK_CHUNK_SIZE
-                        }
-                    };
-                    let message = PeerMessage::new(
-                        MessageType::Request,
-                        piece_index,
-                        block_index * BLOCK_CHUNK_SIZE,
-                        block_size,
-                    )
-                    .to_message();
-                    // eprintln!("{:?} message sent", message);
-                    stream.write_all(&message)?;
-
-                    let mut message_recevied = vec![0u8; block_size + 13]; // initialize message buffer
-                                                                           // let mut message_recevied = Vec::new(); // initialize message buffer
-                    stream
-                        .read_exact(&mut message_recevied)
-                        .context("message read failed")?;
-                    let peer_message = message_recevied
-                        .to_peer_message()
-                        .context("This is not a peer message")?;
-                    if let PeerMessage::Piece(message) = peer_message {
-                        let block = &message.payload[8..];
-                        piece_received.extend_from_slice(block);
-                        eprintln!(
-                            "Blocks: {} of {}, downloaded size: {}",
-                            block_index + 1,
-                            n_blocks,
-                            piece_received.len()
-                        );
-                    }
+            for piece_index in 0..self.piece_count() {
+                eprintln!("=== Pieces: {} of {}", piece_index + 1, self.piece_count());
+                let piece = self.fetch_verified_piece(&mut stream, piece_index)?;
+                piece_received.extend_from_slice(&piece);
+            }
+        } else {
+            bail!("peer did not unchoke us");
+        }
+        Ok(piece_received)
+    }
+
+    /// Download a piece and check it against its hash, re-requesting it
+    /// when the data does not match.
+    fn fetch_verified_piece(
+        &self,
+        stream: &mut TcpStream,
+        piece_index: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        for attempt in 1..=MAX_PIECE_ATTEMPTS {
+            let piece = self.request_piece(stream, piece_index)?;
+            if self.verify_piece(piece_index, &piece) {
+                return Ok(piece);
+            }
+            eprintln!(
+                "piece {} failed its hash check (attempt {} of {}), re-requesting",
+                piece_index, attempt, MAX_PIECE_ATTEMPTS
+            );
+        }
+        bail!(
+            "piece {} did not match its hash after {} attempts",
+            piece_index,
+            MAX_PIECE_ATTEMPTS
+        )
+    }
+
+    /// Request every block of a piece from an unchoked peer.
+    fn request_piece(&self, stream: &mut TcpStream, piece_index: usize) -> anyhow::Result<Vec<u8>> {
+        const BLOCK_CHUNK_SIZE: usize = usize::pow(2, 14);
+        let piece_length = self.piece_len(piece_index);
+        let n_blocks = piece_length.div_ceil(BLOCK_CHUNK_SIZE);
+        let mut piece_received = Vec::<u8>::with_capacity(piece_length);
+        for block_index in 0..n_blocks {
+            let block_size = {
+                if block_index == n_blocks - 1 {
+                    piece_length - BLOCK_CHUNK_SIZE * block_index
+                } else {
+                    BLOCK_CHUNK_SIZE
                 }
+            };
+            let message = PeerMessage::new(
+                MessageType::Request,
+                piece_index,
+                block_index * BLOCK_CHUNK_SIZE,
+                block_size,
+            )
+            .to_message();
+            stream.write_all(&message)?;
+
+            let mut message_recevied = vec![0u8; block_size + 13]; // initialize message buffer
+            stream
+                .read_exact(&mut message_recevied)
+                .context("message read failed")?;
+            let peer_message = message_recevied
+                .to_peer_message()
+                .context("This is not a peer message")?;
+            if let PeerMessage::Piece(message) = peer_message {
+                let block = &message.payload[8..];
+                piece_received.extend_from_slice(block);
+                eprintln!(
+                    "Blocks: {} of {}, downloaded size: {}",
+                    block_index + 1,
+                    n_blocks,
+                    piece_received.len()
+                );
             }
         }
         Ok(piece_received)
     }
+
+    /// Pieces are fetched and checked against v1 hashes, so v2-only
+    /// torrents cannot be downloaded yet.
+    fn check_v1_download(&self) -> anyhow::Result<()> {
+        if !self.version.has_v1() {
+            bail!("downloading v2-only torrents is not supported");
+        }
+        Ok(())
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.piece_hashes.len()
+    }
+
+    /// Length of piece `index`; only the last piece may be shorter than
+    /// `piece_length`.
+    pub fn piece_len(&self, index: usize) -> usize {
+        if index + 1 == self.piece_count() {
+            self.length - index * self.piece_length
+        } else {
+            self.piece_length
+        }
+    }
+
+    pub fn expected_hash(&self, index: usize) -> Option<&[u8; 20]> {
+        self.piece_hashes.get(index)
+    }
+
+    /// Whether `data` is exactly piece `index`.
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> bool {
+        self.expected_hash(index)
+            .is_some_and(|hash| Sha1::digest(data).as_slice() == hash)
+    }
 }
 
 impl fmt::Display for Torrent {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "Tracker URL: {}\nLength: {}\nInfo Hash: {}\nPiece Length: {}\nPiece Hashes:\n{}",
-            &self.url, &self.length, &self.info_hash, &self.piece_length, &self.piece_hashes
-        )
+            "Tracker URL: {}\nLength: {}\nInfo Hash: {}\nPiece Length: {}\n",
+            &self.url,
+            self.layout.data_length(),
+            &self.info_hash,
+            &self.piece_length
+        )?;
+        writeln!(f, "Version: {}", self.version)?;
+        if let Some(hash) = &self.info_hash_v2 {
+            writeln!(f, "Info Hash v2: {}", hash)?;
+        }
+        let meta = &self.meta;
+        let optional = [
+            ("Comment", meta.comment.clone()),
+            ("Created By", meta.created_by.clone()),
+            (
+                "Creation Date",
+                meta.creation_date.map(|date| date.to_string()),
+            ),
+            ("Encoding", meta.encoding.clone()),
+            ("Source", meta.info.source.clone()),
+        ];
+        for (label, value) in optional {
+            if let Some(value) = value {
+                writeln!(f, "{}: {}", label, value)?;
+            }
+        }
+        if self.is_private() {
+            writeln!(f, "Private: yes")?;
+        }
+        for url in &self.web_seeds {
+            writeln!(f, "Web Seed: {}", url)?;
+        }
+        let http_seeds = meta.httpseeds.iter().flat_map(OneOrMany::urls);
+        for url in http_seeds.filter(|url| !url.is_empty()) {
+            writeln!(f, "HTTP Seed: {}", url)?;
+        }
+        if self.trackers.urls().nth(1).is_some() {
+            writeln!(f, "Trackers:")?;
+            for (i, tier) in self.trackers.tiers().iter().enumerate() {
+                writeln!(f, "  tier {}: {}", i + 1, tier.join(" "))?;
+            }
+        }
+        if let Layout::MultiFile { files } = &self.layout {
+            writeln!(f, "Files:")?;
+            for file in files.iter().filter(|file| !file.is_padding()) {
+                writeln!(f, "  {} {}", file.length, file.lossy_path().join("/"))?;
+            }
+        }
+        write!(f, "Piece Hashes:")?;
+        for hash in &self.piece_hashes {
+            write!(f, "\n{}", hex::encode(hash))?;
+        }
+        Ok(())
     }
 }
 pub enum PeerMessage {
@@ -478,6 +1129,9 @@ pub enum PeerMessage {
     Unchoke(Message),
     Request(Message),
     Piece(Message),
+    /// An extension protocol message (BEP 10); the payload starts with the
+    /// extended message id.
+    Extended(Message),
 }
 
 #[repr(u8)]
@@ -488,6 +1142,7 @@ pub enum MessageType {
     Unchoke = 1,
     Request = 6,
     Piece = 7,
+    Extended = 20,
 }
 
 pub trait ToPeerMessage {
@@ -503,7 +1158,7 @@ impl From<MessageType> for u8 {
 impl ToPeerMessage for Vec<u8> {
     fn to_peer_message(&self) -> anyhow::Result<PeerMessage> {
         if self.len() < 5 {
-            panic!("length is too short");
+            bail!("length is too short");
         }
 
         let mut length_bytes = [0u8; 4];
@@ -515,8 +1170,8 @@ impl ToPeerMessage for Vec<u8> {
 
         let message_length = u32::from_be_bytes(length_bytes) as usize;
         // eprintln!("len: {}, message_lenth: {}", self.len(), message_length);
-        if self.len() - 4 < message_length {
-            panic!("message is not completely parsed or wrong message");
+        if message_length == 0 || self.len() - 4 < message_length {
+            bail!("message is not completely parsed or wrong message");
         }
 
         let message = Message {
@@ -534,8 +1189,10 @@ impl ToPeerMessage for Vec<u8> {
             MessageType::Request
         } else if self[4] == MessageType::Piece as u8 {
             MessageType::Piece
+        } else if self[4] == MessageType::Extended as u8 {
+            MessageType::Extended
         } else {
-            panic!("It's not a PeerMessage");
+            bail!("unsupported message id {}", self[4]);
         };
 
         match message_type {
@@ -544,10 +1201,38 @@ impl ToPeerMessage for Vec<u8> {
             MessageType::Unchoke => Ok(PeerMessage::Unchoke(message)),
             MessageType::Request => Ok(PeerMessage::Request(message)),
             MessageType::Piece => Ok(PeerMessage::Piece(message)),
+            MessageType::Extended => Ok(PeerMessage::Extended(message)),
+        }
+    }
+}
+
+/// Read one length-prefixed peer message, skipping keep-alives. The
+/// returned bytes include the length prefix.
+pub fn read_message(stream: &mut impl Read) -> anyhow::Result<Vec<u8>> {
+    loop {
+        let mut length = [0u8; 4];
+        stream
+            .read_exact(&mut length)
+            .context("read message length")?;
+        let message_length = u32::from_be_bytes(length) as usize;
+        if message_length == 0 {
+            continue;
+        }
+        if message_length > MAX_MESSAGE_LENGTH {
+            bail!("peer message of {} bytes is too long", message_length);
         }
+        let mut message = length.to_vec();
+        message.resize(4 + message_length, 0);
+        stream
+            .read_exact(&mut message[4..])
+            .context("read message body")?;
+        return Ok(message);
     }
 }
 
+/// Enough for a 16 KiB block or metadata piece plus headers.
+const MAX_MESSAGE_LENGTH: usize = 1 << 17;
+
 pub struct Message {
     pub length: [u8; 4],
     // pub id: u8,
@@ -574,8 +1259,18 @@ impl PeerMessage {
             MessageType::Unchoke => PeerMessage::Unchoke(message),
             MessageType::Request => PeerMessage::Request(message),
             MessageType::Piece => PeerMessage::Piece(message),
+            MessageType::Extended => PeerMessage::Extended(message),
         }
     }
+    /// An extension message for extended message id `id`.
+    pub fn extended(id: u8, payload: &[u8]) -> Self {
+        let mut body = vec![id];
+        body.extend_from_slice(payload);
+        PeerMessage::Extended(Message {
+            length: (1 + body.len() as u32).to_be_bytes(),
+            payload: body,
+        })
+    }
     pub fn to_message(&self) -> Vec<u8> {
         let mut vec: Vec<u8> = Vec::new();
         // vec.push(19u8);
@@ -585,6 +1280,7 @@ impl PeerMessage {
             PeerMessage::Unchoke(message) => (message, MessageType::Unchoke.into()),
             PeerMessage::Request(message) => (message, MessageType::Request.into()),
             PeerMessage::Piece(message) => (message, MessageType::Piece.into()),
+            PeerMessage::Extended(message) => (message, MessageType::Extended.into()),
         };
         vec.extend(message.length);
         vec.push(id);
@@ -592,3 +1288,381 @@ impl PeerMessage {
         vec
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_bytes::Bytes;
+
+    const MULTI_FILE: &[u8] = b"d8:announce3:url4:infod5:filesld6:lengthi3e4:pathl1:aeed6:lengthi4e6:md5sum32:0123456789abcdef0123456789abcdef4:pathl3:sub1:beee4:name3:dir12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+
+    #[test]
+    fn multi_file_layout() {
+        let torrent = Torrent::from_bytes(MULTI_FILE).unwrap();
+        assert_eq!(torrent.name, "dir");
+        assert_eq!(torrent.length, 7);
+        let Layout::MultiFile { files } = &torrent.layout else {
+            panic!("expected a multi-file layout");
+        };
+        assert_eq!(files[1].lossy_path(), ["sub", "b"]);
+        assert!(files[1].md5sum.is_some());
+        assert!(torrent.to_string().contains("Files:\n  3 a\n  4 sub/b\n"));
+
+        let dir = tempfile::tempdir().unwrap();
+        torrent.write_files(b"abcdefg", dir.path()).unwrap();
+        assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"abc");
+        assert_eq!(fs::read(dir.path().join("sub").join("b")).unwrap(), b"defg");
+        assert!(torrent.write_files(b"abc", dir.path()).is_err());
+    }
+
+    #[test]
+    fn optional_keys_of_the_wrong_type_are_set_aside() {
+        let bytes: &[u8] = b"d8:announce3:url13:announce-listl1:a1:be13:creation date9:yesterday4:infod6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let torrent = Torrent::from_bytes(bytes).unwrap();
+        assert_eq!(torrent.meta.creation_date, None);
+        assert_eq!(torrent.meta.announce_list, None);
+        assert_eq!(
+            torrent.meta.extra[Bytes::new(b"creation date")],
+            BValue::Bytes(b"yesterday".to_vec())
+        );
+        assert_eq!(torrent.trackers.urls().collect::<Vec<_>>(), ["url"]);
+        assert_eq!(torrent.warnings.len(), 2);
+        assert!(torrent.warnings[0].contains("`announce-list`"));
+        assert_eq!(bencode::to_bytes(&torrent.meta).unwrap(), bytes);
+    }
+
+    #[test]
+    fn empty_seed_urls_are_skipped() {
+        let bytes: &[u8] = b"d8:announce3:url9:httpseedsl0:e4:infod6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-list0:e";
+        let torrent = Torrent::from_bytes(bytes).unwrap();
+        assert!(torrent.web_seeds.is_empty());
+        let shown = torrent.to_string();
+        assert!(!shown.contains("Web Seed"));
+        assert!(!shown.contains("HTTP Seed"));
+        assert_eq!(bencode::to_bytes(&torrent.meta).unwrap(), bytes);
+    }
+
+    #[test]
+    fn unknown_keys_keep_integers_of_any_size() {
+        let huge = "9".repeat(60);
+        let input = format!(
+            "d8:announce3:url4:infod6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:x-sizei{huge}ee7:x-totali-{huge}ee"
+        );
+        let torrent = Torrent::from_bytes(input.as_bytes()).unwrap();
+        assert_eq!(
+            torrent.meta.extra[Bytes::new(b"x-total")],
+            BValue::BigInt(format!("-{huge}"))
+        );
+        assert_eq!(
+            torrent.meta.info.extra[Bytes::new(b"x-size")],
+            BValue::BigInt(huge)
+        );
+        assert_eq!(bencode::to_bytes(&torrent.meta).unwrap(), input.as_bytes());
+    }
+
+    #[test]
+    fn unknown_keys_may_be_any_bytes() {
+        let bytes: &[u8] = b"d8:announce3:url4:infod6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaa1:\xfe1:xe2:\xffai1ee";
+        let torrent = Torrent::from_bytes(bytes).unwrap();
+        assert_eq!(torrent.meta.extra[Bytes::new(b"\xffa")], BValue::Int(1));
+        assert_eq!(
+            torrent.meta.info.extra[Bytes::new(b"\xfe")],
+            BValue::Bytes(b"x".to_vec())
+        );
+        assert_eq!(bencode::to_bytes(&torrent.meta).unwrap(), bytes);
+    }
+
+    #[test]
+    fn non_utf8_names_are_kept_as_bytes() {
+        let bytes: &[u8] = b"d4:infod5:filesld6:lengthi3e4:pathl2:\xffaeee4:name2:\xfe!12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let torrent = Torrent::from_bytes(bytes).unwrap();
+        assert_eq!(torrent.name, "\u{fffd}!");
+        assert_eq!(torrent.meta.info.name.as_slice(), b"\xfe!");
+        assert!(torrent.to_string().contains("Files:\n  3 \u{fffd}a\n"));
+        assert_eq!(bencode::to_bytes(&torrent.meta).unwrap(), bytes);
+
+        let dir = tempfile::tempdir().unwrap();
+        torrent.write_files(b"abc", dir.path()).unwrap();
+        assert_eq!(fs::read(dir.path().join("\u{fffd}a")).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn unsafe_file_paths_are_rejected() {
+        for path in [vec![], vec![".."], vec!["a", ""], vec!["/etc"]] {
+            let file = FileEntry {
+                length: 0,
+                path: path.iter().map(|s| ByteBuf::from(*s)).collect(),
+                md5sum: None,
+                attr: None,
+            };
+            assert!(file.relative_path().is_err(), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn info_hash_covers_only_info() {
+        let info = b"d6:lengthi3e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let mut bytes = b"d8:announce3:url4:info".to_vec();
+        bytes.extend_from_slice(info);
+        bytes.extend_from_slice(b"8:url-listl1:uee");
+
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            torrent.info_hash.to_string(),
+            hex::encode(Sha1::digest(info))
+        );
+    }
+
+    #[test]
+    fn piece_hashes_and_verification() {
+        let first = Sha1::digest(b"abcd");
+        let last = Sha1::digest(b"ef");
+        let mut bytes =
+            b"d8:announce3:url4:infod6:lengthi6e4:name1:a12:piece lengthi4e6:pieces40:".to_vec();
+        bytes.extend_from_slice(&first);
+        bytes.extend_from_slice(&last);
+        bytes.extend_from_slice(b"ee");
+
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.piece_count(), 2);
+        assert_eq!(torrent.piece_len(0), 4);
+        assert_eq!(torrent.piece_len(1), 2);
+        assert_eq!(
+            torrent.expected_hash(1).unwrap().as_slice(),
+            last.as_slice()
+        );
+        assert!(torrent.expected_hash(2).is_none());
+        assert!(torrent.verify_piece(0, b"abcd"));
+        assert!(!torrent.verify_piece(0, b"abce"));
+        assert!(!torrent.verify_piece(2, b""));
+    }
+
+    #[test]
+    fn malformed_pieces_are_rejected() {
+        // 19 bytes of hashes
+        let short = b"d8:announce3:url4:infod6:lengthi3e4:name1:a12:piece lengthi4e6:pieces19:aaaaaaaaaaaaaaaaaaaee";
+        assert!(Torrent::from_bytes(short).is_err());
+        // two hashes for a single piece
+        let extra = b"d8:announce3:url4:infod6:lengthi3e4:name1:a12:piece lengthi4e6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaee";
+        assert!(Torrent::from_bytes(extra).is_err());
+    }
+
+    #[test]
+    fn info_hash_encodings() {
+        let hex = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+        let hash: InfoHash = hex.parse().unwrap();
+        assert_eq!(hash.to_string(), hex);
+        assert_eq!(hash.to_base32(), "22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7");
+        assert_eq!(
+            hash.to_base32().to_lowercase().parse::<InfoHash>(),
+            Ok(hash)
+        );
+        assert_eq!(hex.to_uppercase().parse::<InfoHash>(), Ok(hash));
+        assert_eq!(
+            hash.to_url(),
+            "%D6%9F%91%E6%B2%AELT%24h%D1%07%3Aq%D4%EA%13%87%9A%7F"
+        );
+
+        assert_eq!(
+            "abc".parse::<InfoHash>(),
+            Err(ParseInfoHashError::Length(3))
+        );
+        assert_eq!(
+            hex.replace('d', "g").parse::<InfoHash>(),
+            Err(ParseInfoHashError::Hex)
+        );
+        assert_eq!(
+            "1".repeat(32).parse::<InfoHash>(),
+            Err(ParseInfoHashError::Base32)
+        );
+    }
+
+    #[test]
+    fn handshake_round_trip() {
+        let hash: InfoHash = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f".parse().unwrap();
+        let message = HandShake {
+            reserved: [0u8; 8],
+            info_hash: hash,
+            peer_id: b"00112233445566778899".to_vec(),
+        }
+        .to_message();
+        let handshake = message.to_handshake();
+        assert_eq!(handshake.info_hash, hash);
+        assert_eq!(handshake.peer_id, b"00112233445566778899");
+        assert!(!handshake.supports_extensions());
+        assert!(handshake
+            .with_extensions()
+            .to_message()
+            .to_handshake()
+            .supports_extensions());
+    }
+
+    #[test]
+    fn announce_list_tiers() {
+        let bytes = b"d8:announce1:x13:announce-listll1:ael1:b1:cee4:infod6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let torrent = Torrent::from_bytes(bytes).unwrap();
+        assert_eq!(torrent.url, "a");
+        assert_eq!(torrent.trackers.tiers().len(), 2);
+        assert!(torrent.to_string().contains("Trackers:\n  tier 1: a\n"));
+    }
+
+    #[test]
+    fn torrent_from_info_bytes() {
+        let info = b"d6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let trackers = TrackerTiers::new(vec![vec!["http://t".into()]]);
+        let torrent = Torrent::from_info_bytes(info, trackers).unwrap();
+        assert_eq!(torrent.url, "http://t");
+        assert_eq!(torrent.info_hash.as_bytes(), Sha1::digest(info).as_slice());
+        assert!(Torrent::from_info_bytes(b"d4:name1:ae", TrackerTiers::default()).is_err());
+    }
+
+    #[test]
+    fn extended_messages() {
+        let message = PeerMessage::extended(3, b"d1:ai1ee").to_message();
+        assert_eq!(&message[..6], [0, 0, 0, 10, 20, 3]);
+        let mut reader = [&[0u8, 0, 0, 0][..], &message].concat();
+        let read = read_message(&mut reader.as_slice()).unwrap();
+        assert_eq!(read, message);
+        let Ok(PeerMessage::Extended(parsed)) = read.to_peer_message() else {
+            panic!("expected an extended message");
+        };
+        assert_eq!(parsed.payload, b"\x03d1:ai1ee");
+        reader.truncate(8);
+        assert!(read_message(&mut reader.as_slice()).is_err());
+        assert!(vec![0u8, 0, 0, 1, 99].to_peer_message().is_err());
+    }
+
+    #[test]
+    fn optional_and_unknown_keys_round_trip() {
+        let bytes: &[u8] = b"d8:announce3:url7:comment2:hi10:created by4:test13:creation datei1700000000e8:encoding5:UTF-89:httpseedsl6:http:se4:infod6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1e6:source3:src6:x-infoi7ee8:url-list6:http:w7:x-extrali1eee";
+        let torrent = Torrent::from_bytes(bytes).unwrap();
+        let meta = &torrent.meta;
+        assert_eq!(meta.comment.as_deref(), Some("hi"));
+        assert_eq!(meta.creation_date, Some(1700000000));
+        assert_eq!(meta.encoding.as_deref(), Some("UTF-8"));
+        assert_eq!(meta.httpseeds, Some(OneOrMany::Many(vec!["http:s".into()])));
+        assert_eq!(meta.info.private, Some(1));
+        assert_eq!(meta.info.source.as_deref(), Some("src"));
+        assert_eq!(torrent.web_seeds, ["http:w"]);
+        assert_eq!(
+            meta.extra[Bytes::new(b"x-extra")],
+            BValue::List(vec![BValue::Int(1)])
+        );
+        assert_eq!(meta.info.extra[Bytes::new(b"x-info")], BValue::Int(7));
+        assert_eq!(bencode::to_bytes(meta).unwrap(), bytes);
+
+        let shown = torrent.to_string();
+        assert!(shown.contains("Comment: hi\nCreated By: test\n"));
+        assert!(shown.contains("Web Seed: http:w\nHTTP Seed: http:s\n"));
+
+        let json = torrent.to_json().unwrap();
+        assert_eq!(json["created by"], "test");
+        assert_eq!(
+            json["info"]["pieces"][0],
+            hex::encode(b"aaaaaaaaaaaaaaaaaaaa")
+        );
+        assert_eq!(json["info hash"], torrent.info_hash.to_string());
+    }
+
+    #[test]
+    fn v2_only_torrent() {
+        use crate::merkle::BLOCK_SIZE;
+        let big = vec![7u8; 3 * BLOCK_SIZE];
+        let bytes = v2::test_metainfo(&[("big", &big), ("small", b"abc")], BLOCK_SIZE);
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.version, MetaVersion::V2);
+        assert_eq!(torrent.length, big.len() + 3);
+        assert_eq!(torrent.v2_files[0].piece_layer.len(), 3);
+        assert!(torrent.v2_files[1].piece_layer.is_empty());
+
+        let span = Decoder::new(&bytes).find_span(&["info"]).unwrap();
+        let v2_hash = InfoHashV2(Sha256::digest(&bytes[span]));
+        assert_eq!(torrent.info_hash_v2, Some(v2_hash));
+        assert_eq!(torrent.info_hash, v2_hash.truncated());
+
+        let shown = torrent.to_string();
+        assert!(shown.contains("Version: 2\n"));
+        assert!(shown.contains(&format!("Info Hash v2: {}\n", v2_hash)));
+        assert!(shown.contains("Files:\n  49152 big\n  3 small\n"));
+        assert_eq!(
+            torrent.to_json().unwrap()["info hash v2"],
+            v2_hash.to_string()
+        );
+
+        // `piece layers` comes last; corrupt the final byte of its hash
+        let mut bad = bytes.clone();
+        let len = bad.len();
+        bad[len - 3] ^= 1;
+        assert!(Torrent::from_bytes(&bad).is_err());
+    }
+
+    struct CountingSource {
+        source: PeerSource,
+        addr: &'static str,
+        calls: std::rc::Rc<std::cell::Cell<usize>>,
+    }
+
+    impl PeerProvider for CountingSource {
+        fn source(&self) -> PeerSource {
+            self.source
+        }
+        fn peers(&mut self, _info_hash: &InfoHash) -> anyhow::Result<Vec<Peer>> {
+            self.calls.set(self.calls.get() + 1);
+            Ok(vec![Peer {
+                addr: self.addr.parse().unwrap(),
+                peer_id: None,
+            }])
+        }
+    }
+
+    fn torrent_with_sources(private: bool) -> (Torrent, std::rc::Rc<std::cell::Cell<usize>>) {
+        let info: &[u8] = if private {
+            b"d6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1ee"
+        } else {
+            b"d6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae"
+        };
+        let mut torrent = Torrent::from_info_bytes(info, TrackerTiers::default()).unwrap();
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        for (source, addr) in [
+            (PeerSource::Dht, "10.0.0.1:1"),
+            (PeerSource::Pex, "10.0.0.2:2"),
+            (PeerSource::Lsd, "10.0.0.3:3"),
+            (PeerSource::Hint, "10.0.0.1:1"),
+        ] {
+            torrent.add_peer_source(Box::new(CountingSource {
+                source,
+                addr,
+                calls: calls.clone(),
+            }));
+        }
+        (torrent, calls)
+    }
+
+    #[test]
+    fn private_torrent_skips_hints_and_other_sources() {
+        let (mut torrent, calls) = torrent_with_sources(true);
+        assert!(torrent.is_private());
+        assert!(torrent.to_string().contains("Private: yes\n"));
+        assert!(torrent.allows_peer_source(PeerSource::Tracker));
+        assert!(!torrent.allows_peer_source(PeerSource::Hint));
+        assert!(!torrent.allows_peer_source(PeerSource::Dht));
+        // no trackers, and the hint, DHT, PEX and LSD all refused
+        assert!(torrent.find_peers().is_err());
+        assert_eq!(calls.get(), 0);
+    }
+
+    #[test]
+    fn public_torrent_merges_all_sources() {
+        let (mut torrent, calls) = torrent_with_sources(false);
+        assert!(!torrent.is_private());
+        assert!(!torrent.to_string().contains("Private"));
+        let peers: Vec<String> = torrent
+            .find_peers()
+            .unwrap()
+            .iter()
+            .map(Peer::to_string)
+            .collect();
+        assert_eq!(peers, ["10.0.0.1:1", "10.0.0.2:2", "10.0.0.3:3"]);
+        assert_eq!(calls.get(), 4);
+    }
+}
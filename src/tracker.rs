@@ -0,0 +1,449 @@
+//! # Tracker
+//!
+//! interpret HTTP tracker announce responses and choose between the
+//! trackers of a multi-tracker torrent (BEP 12)
+//!
+
+use crate::bencode::{self, BValue, Bencode, Progress, StreamDecoder};
+use crate::torrent::InfoHash;
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// A peer handed out by a tracker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// Only present in the non-compact dictionary model.
+    pub peer_id: Option<Vec<u8>>,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.addr)
+    }
+}
+
+/// A successful announce response.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AnnounceResponse {
+    pub interval: Option<i64>,
+    pub min_interval: Option<i64>,
+    pub complete: Option<i64>,
+    pub incomplete: Option<i64>,
+    pub warning_message: Option<String>,
+    /// Peers from `peers` followed by those from `peers6`.
+    pub peers: Vec<Peer>,
+}
+
+#[derive(Deserialize)]
+struct RawResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+    interval: Option<i64>,
+    #[serde(rename = "min interval")]
+    min_interval: Option<i64>,
+    complete: Option<i64>,
+    incomplete: Option<i64>,
+    peers: Option<RawPeers>,
+    peers6: Option<ByteBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPeers {
+    Compact(ByteBuf),
+    Dictionaries(Vec<RawPeer>),
+}
+
+#[derive(Deserialize)]
+struct RawPeer {
+    ip: String,
+    port: u16,
+    #[serde(rename = "peer id")]
+    peer_id: Option<ByteBuf>,
+}
+
+impl AnnounceResponse {
+    /// Interpret a bencoded announce response. A `failure reason` from the
+    /// tracker is turned into an error.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<AnnounceResponse> {
+        let value = bytes.bdecode().context("decode announce response")?;
+        AnnounceResponse::from_value(&value)
+    }
+
+    /// Read a response body as it arrives, stopping at the end of the
+    /// bencoded value. Malformed or oversized bodies fail without reading
+    /// the rest.
+    pub fn read_from(mut body: impl Read) -> anyhow::Result<AnnounceResponse> {
+        let mut decoder = StreamDecoder::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            let read = body.read(&mut chunk).context("read announce response")?;
+            if read == 0 {
+                bail!("the announce response ended early");
+            }
+            match decoder
+                .push(&chunk[..read])
+                .context("decode announce response")?
+            {
+                Progress::NeedMoreData => continue,
+                Progress::Complete { value, .. } => return AnnounceResponse::from_value(&value),
+            }
+        }
+    }
+
+    fn from_value(value: &BValue) -> anyhow::Result<AnnounceResponse> {
+        let raw: RawResponse = bencode::from_value(value).context("read announce response")?;
+        if let Some(reason) = raw.failure_reason {
+            bail!("tracker failure: {}", reason);
+        }
+
+        let mut peers = match raw.peers {
+            Some(RawPeers::Compact(bytes)) => compact_peers_v4(&bytes)?,
+            Some(RawPeers::Dictionaries(list)) => list
+                .into_iter()
+                .map(|peer| {
+                    let ip: IpAddr = peer
+                        .ip
+                        .parse()
+                        .with_context(|| format!("invalid peer ip `{}`", peer.ip))?;
+                    Ok(Peer {
+                        addr: SocketAddr::new(ip, peer.port),
+                        peer_id: peer.peer_id.map(ByteBuf::into_vec),
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+        if let Some(bytes) = raw.peers6 {
+            peers.extend(compact_peers_v6(&bytes)?);
+        }
+
+        Ok(AnnounceResponse {
+            interval: raw.interval,
+            min_interval: raw.min_interval,
+            complete: raw.complete,
+            incomplete: raw.incomplete,
+            warning_message: raw.warning_message,
+            peers,
+        })
+    }
+}
+
+/// Parse the compact IPv4 model: 4 address bytes and 2 port bytes per peer.
+pub fn compact_peers_v4(bytes: &[u8]) -> anyhow::Result<Vec<Peer>> {
+    if !bytes.len().is_multiple_of(6) {
+        bail!(
+            "compact peers length {} is not a multiple of 6",
+            bytes.len()
+        );
+    }
+    Ok(bytes
+        .chunks(6)
+        .map(|arr| {
+            let ip = Ipv4Addr::new(arr[0], arr[1], arr[2], arr[3]);
+            let port = u16::from_be_bytes([arr[4], arr[5]]);
+            Peer {
+                addr: SocketAddr::new(ip.into(), port),
+                peer_id: None,
+            }
+        })
+        .collect())
+}
+
+/// Parse the compact IPv6 model (BEP 7): 16 address bytes and 2 port bytes
+/// per peer.
+pub fn compact_peers_v6(bytes: &[u8]) -> anyhow::Result<Vec<Peer>> {
+    if !bytes.len().is_multiple_of(18) {
+        bail!(
+            "compact peers6 length {} is not a multiple of 18",
+            bytes.len()
+        );
+    }
+    Ok(bytes
+        .chunks(18)
+        .map(|arr| {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&arr[..16]);
+            let port = u16::from_be_bytes([arr[16], arr[17]]);
+            Peer {
+                addr: SocketAddr::new(Ipv6Addr::from(octets).into(), port),
+                peer_id: None,
+            }
+        })
+        .collect())
+}
+
+/// Send a started announce for `info_hash` to the HTTP tracker at `url`.
+pub fn announce(url: &str, info_hash: &InfoHash, left: usize) -> anyhow::Result<AnnounceResponse> {
+    let left = left.to_string();
+    let query_params = vec![
+        ("uploaded", "0"),
+        ("downloaded", "0"),
+        ("compact", "1"),
+        ("left", &left),
+        ("peer_id", "00112233445566778899"),
+        ("port", "6881"),
+    ];
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let url_with_query = format!(
+        "{}{}{}&info_hash={}",
+        url,
+        separator,
+        serde_urlencoded::to_string(query_params)?,
+        info_hash.to_url()
+    );
+
+    let response = reqwest::blocking::get(url_with_query)?.error_for_status()?;
+    AnnounceResponse::read_from(response).context("could not decode the tracker response")
+}
+
+/// Peers from several responses with duplicate addresses removed, in the
+/// order they were first seen.
+pub fn merge_peers<'a>(responses: impl IntoIterator<Item = &'a AnnounceResponse>) -> Vec<Peer> {
+    let mut seen = HashSet::new();
+    responses
+        .into_iter()
+        .flat_map(|response| &response.peers)
+        .filter(|peer| seen.insert(peer.addr))
+        .cloned()
+        .collect()
+}
+
+/// Announce URLs grouped into tiers as described by BEP 12. Trackers are
+/// tried tier by tier, in order within a tier, and one that answers is
+/// moved to the front of its tier.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerTiers {
+    /// Tiers as given, dropping empty URLs and tiers.
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        TrackerTiers {
+            tiers: tiers
+                .into_iter()
+                .map(|tier| tier.into_iter().filter(|url| !url.is_empty()).collect())
+                .filter(|tier: &Vec<String>| !tier.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Trackers of a torrent: `announce-list` when present and non-empty,
+    /// else a single tier holding `announce`.
+    pub fn from_metainfo(announce: Option<&str>, announce_list: Option<&[Vec<String>]>) -> Self {
+        let tiers = TrackerTiers::new(announce_list.map(<[_]>::to_vec).unwrap_or_default());
+        if !tiers.is_empty() {
+            return tiers;
+        }
+        TrackerTiers::new(
+            announce
+                .map(|url| vec![vec![url.to_string()]])
+                .unwrap_or_default(),
+        )
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        self.tiers.iter().flatten().map(String::as_str)
+    }
+
+    pub fn first(&self) -> Option<&str> {
+        self.urls().next()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Randomise the order within each tier, as clients do when loading a
+    /// torrent.
+    pub fn shuffle(&mut self) {
+        let mut rng = RandomState::new().build_hasher().finish() | 1;
+        for tier in &mut self.tiers {
+            for i in (1..tier.len()).rev() {
+                rng ^= rng << 13;
+                rng ^= rng >> 7;
+                rng ^= rng << 17;
+                tier.swap(i, (rng % (i as u64 + 1)) as usize);
+            }
+        }
+    }
+
+    /// Call `request` on each tracker in turn until one succeeds, promote
+    /// that tracker and return its URL with the result.
+    pub fn try_each<T>(
+        &mut self,
+        mut request: impl FnMut(&str) -> anyhow::Result<T>,
+    ) -> anyhow::Result<(String, T)> {
+        let mut last_error = None;
+        for tier in &mut self.tiers {
+            for i in 0..tier.len() {
+                match request(&tier[i]) {
+                    Ok(value) => {
+                        let url = tier.remove(i);
+                        tier.insert(0, url.clone());
+                        return Ok((url, value));
+                    }
+                    Err(error) => {
+                        eprintln!("tracker {} failed: {:#}", tier[i], error);
+                        last_error = Some(error);
+                    }
+                }
+            }
+        }
+        match last_error {
+            Some(error) => Err(error.context("no tracker responded")),
+            None => bail!("the torrent has no trackers"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_response() {
+        let response = AnnounceResponse::from_bytes(
+            b"d8:intervali60e5:peers12:\x7f\x00\x00\x01\x1a\xe1\xc0\xa8\x00\x02\x00\x50\
+              6:peers618:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe1e",
+        )
+        .unwrap();
+        assert_eq!(response.interval, Some(60));
+        let peers: Vec<String> = response.peers.iter().map(Peer::to_string).collect();
+        assert_eq!(peers, ["127.0.0.1:6881", "192.168.0.2:80", "[::1]:6881"]);
+    }
+
+    #[test]
+    fn dictionary_response() {
+        let response = AnnounceResponse::from_bytes(
+            b"d5:peersld2:ip8:10.0.0.17:peer id20:-XX0001-0123456789ab4:porti51413eeee",
+        )
+        .unwrap();
+        assert_eq!(response.peers[0].addr, "10.0.0.1:51413".parse().unwrap());
+        assert_eq!(
+            response.peers[0].peer_id.as_deref(),
+            Some(&b"-XX0001-0123456789ab"[..])
+        );
+    }
+
+    #[test]
+    fn failure_and_malformed_responses() {
+        let err = AnnounceResponse::from_bytes(b"d14:failure reason9:not founde").unwrap_err();
+        assert_eq!(err.to_string(), "tracker failure: not found");
+        assert!(AnnounceResponse::from_bytes(b"d5:peers5:abcdee").is_err());
+    }
+
+    #[test]
+    fn response_read_in_chunks() {
+        /// Hands out at most three bytes per read, like a slow connection.
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = buf.len().min(3).min(self.0.len());
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let body = b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+        let response = AnnounceResponse::read_from(Trickle(body)).unwrap();
+        assert_eq!(response.interval, Some(60));
+        assert_eq!(response.peers[0].to_string(), "127.0.0.1:6881");
+
+        assert!(AnnounceResponse::read_from(Trickle(&body[..10])).is_err());
+        assert!(AnnounceResponse::read_from(Trickle(b"<html>")).is_err());
+    }
+
+    fn tiers() -> TrackerTiers {
+        TrackerTiers::new(vec![
+            vec!["a1".into(), "a2".into(), "".into()],
+            vec![],
+            vec!["b1".into(), "b2".into()],
+        ])
+    }
+
+    #[test]
+    fn tiers_from_metainfo() {
+        let list = tiers().tiers().to_vec();
+        assert_eq!(list.len(), 2);
+        assert_eq!(
+            TrackerTiers::from_metainfo(Some("x"), Some(&list)).tiers(),
+            list
+        );
+        assert_eq!(
+            TrackerTiers::from_metainfo(Some("x"), Some(&[vec![]])).tiers(),
+            [vec!["x".to_string()]]
+        );
+        assert!(TrackerTiers::from_metainfo(None, None).is_empty());
+
+        let mut shuffled = tiers();
+        shuffled.shuffle();
+        for (tier, original) in shuffled.tiers().iter().zip(tiers().tiers()) {
+            let mut tier = tier.clone();
+            tier.sort();
+            assert_eq!(&tier, original);
+        }
+    }
+
+    #[test]
+    fn failover_promotes_responsive_tracker() {
+        let mut trackers = tiers();
+        let mut tried = Vec::new();
+        let (url, value) = trackers
+            .try_each(|url| {
+                tried.push(url.to_string());
+                if url == "b2" {
+                    Ok(7)
+                } else {
+                    bail!("down")
+                }
+            })
+            .unwrap();
+        assert_eq!((url.as_str(), value), ("b2", 7));
+        assert_eq!(tried, ["a1", "a2", "b1", "b2"]);
+        assert_eq!(trackers.tiers()[1], ["b2", "b1"]);
+        assert_eq!(trackers.tiers()[0], ["a1", "a2"]);
+
+        let err = trackers.try_each(|_| -> anyhow::Result<()> { bail!("down") });
+        assert_eq!(err.unwrap_err().to_string(), "no tracker responded");
+        let err = TrackerTiers::default().try_each(|_| Ok(()));
+        assert_eq!(err.unwrap_err().to_string(), "the torrent has no trackers");
+    }
+
+    #[test]
+    fn merged_peers_are_unique() {
+        let peer = |addr: &str| Peer {
+            addr: addr.parse().unwrap(),
+            peer_id: None,
+        };
+        let first = AnnounceResponse {
+            peers: vec![peer("10.0.0.1:1"), peer("10.0.0.2:2")],
+            ..AnnounceResponse::default()
+        };
+        let second = AnnounceResponse {
+            peers: vec![peer("10.0.0.2:2"), peer("10.0.0.3:3")],
+            ..AnnounceResponse::default()
+        };
+        let merged: Vec<String> = merge_peers([&first, &second])
+            .iter()
+            .map(Peer::to_string)
+            .collect();
+        assert_eq!(merged, ["10.0.0.1:1", "10.0.0.2:2", "10.0.0.3:3"]);
+    }
+}
@@ -0,0 +1,326 @@
+//! # V2
+//!
+//! BitTorrent v2 metainfo (BEP 52): the `file tree`, per-file `pieces
+//! root` and `piece layers`, and the SHA-256 info hash
+//!
+
+use crate::bencode::BValue;
+use crate::merkle::{self, Hash, BLOCK_SIZE};
+use crate::torrent::{self, InfoHash};
+use anyhow::{bail, Context};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Which hash trees a torrent carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaVersion {
+    V1,
+    V2,
+    /// Both v1 `pieces` and a v2 `file tree` describing the same data.
+    Hybrid,
+}
+
+impl MetaVersion {
+    pub fn has_v1(self) -> bool {
+        self != MetaVersion::V2
+    }
+
+    pub fn has_v2(self) -> bool {
+        self != MetaVersion::V1
+    }
+}
+
+impl fmt::Display for MetaVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            MetaVersion::V1 => "1",
+            MetaVersion::V2 => "2",
+            MetaVersion::Hybrid => "hybrid (1 and 2)",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The SHA-256 of the bencoded `info` dictionary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InfoHashV2(pub [u8; 32]);
+
+impl InfoHashV2 {
+    /// The first 20 bytes, used where the protocol has room for a v1
+    /// hash, e.g. in the peer handshake.
+    pub fn truncated(&self) -> InfoHash {
+        let mut hash = [0u8; 20];
+        hash.copy_from_slice(&self.0[..20]);
+        InfoHash(hash)
+    }
+}
+
+impl fmt::Display for InfoHashV2 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+/// One file of the v2 `file tree`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct V2File {
+    /// Path components below the torrent's name, decoded lossily.
+    pub path: Vec<String>,
+    pub length: usize,
+    /// Root of the file's merkle tree; absent for empty files.
+    pub pieces_root: Option<Hash>,
+    /// Hashes of the file's pieces from `piece layers`; empty for files of
+    /// at most one piece.
+    pub piece_layer: Vec<Hash>,
+}
+
+impl V2File {
+    pub fn piece_count(&self, piece_length: usize) -> usize {
+        self.length.div_ceil(piece_length)
+    }
+}
+
+/// The files of a `file tree`, depth first in key order, which is the order
+/// their data is laid out in.
+pub fn parse_file_tree(tree: &BValue) -> anyhow::Result<Vec<V2File>> {
+    let mut files = Vec::new();
+    walk(tree, &mut Vec::new(), &mut files)?;
+    if files.is_empty() {
+        bail!("the file tree has no files");
+    }
+    Ok(files)
+}
+
+fn walk(node: &BValue, path: &mut Vec<String>, files: &mut Vec<V2File>) -> anyhow::Result<()> {
+    let dict = node
+        .as_dict()
+        .context("file tree node is not a dictionary")?;
+    if let Some(leaf) = dict.get(&b""[..]) {
+        if dict.len() != 1 || path.is_empty() {
+            bail!("file tree entry {:?} is both a file and a directory", path);
+        }
+        let length = leaf
+            .get("length")
+            .and_then(BValue::as_int)
+            .and_then(|n| usize::try_from(n).ok())
+            .with_context(|| format!("file {:?} has no valid length", path))?;
+        let pieces_root = match leaf.get("pieces root").map(BValue::as_bytes) {
+            Some(Some(root)) => Some(
+                Hash::try_from(root)
+                    .map_err(|_| anyhow::anyhow!("pieces root of {:?} is not 32 bytes", path))?,
+            ),
+            Some(None) => bail!("pieces root of {:?} is not a byte string", path),
+            None if length > 0 => bail!("file {:?} has no pieces root", path),
+            None => None,
+        };
+        files.push(V2File {
+            path: path.clone(),
+            length,
+            pieces_root,
+            piece_layer: Vec::new(),
+        });
+        return Ok(());
+    }
+    for (name, child) in dict {
+        path.push(torrent::lossy(name));
+        walk(child, path, files)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+/// Build a `file tree` from files in layout order.
+pub fn file_tree(files: &[V2File]) -> BValue {
+    let mut root = BTreeMap::new();
+    for file in files {
+        let mut leaf = BTreeMap::from([(b"length".to_vec(), BValue::Int(file.length as i64))]);
+        if let Some(pieces_root) = file.pieces_root {
+            leaf.insert(b"pieces root".to_vec(), BValue::Bytes(pieces_root.to_vec()));
+        }
+        let mut node = &mut root;
+        for component in &file.path {
+            let child = node
+                .entry(component.as_bytes().to_vec())
+                .or_insert_with(|| BValue::Dict(BTreeMap::new()));
+            let BValue::Dict(child) = child else {
+                unreachable!("file tree nodes are dictionaries");
+            };
+            node = child;
+        }
+        node.insert(Vec::new(), BValue::Dict(leaf));
+    }
+    BValue::Dict(root)
+}
+
+/// v2 piece lengths are powers of two of at least one 16 KiB block.
+pub fn check_piece_length(piece_length: usize) -> anyhow::Result<()> {
+    if !piece_length.is_power_of_two() || piece_length < BLOCK_SIZE {
+        bail!(
+            "piece length {} must be a power of two of at least 16 KiB",
+            piece_length
+        );
+    }
+    Ok(())
+}
+
+/// Fill in each file's piece layer from `piece layers`, checking that the
+/// layer has one hash per piece and hashes up to the file's pieces root.
+pub fn attach_piece_layers(
+    files: &mut [V2File],
+    layers: &BTreeMap<ByteBuf, ByteBuf>,
+    piece_length: usize,
+) -> anyhow::Result<()> {
+    for file in files.iter_mut().filter(|file| file.length > piece_length) {
+        let root = file.pieces_root.expect("non-empty files have a root");
+        let layer = layers
+            .get(serde_bytes::Bytes::new(&root))
+            .with_context(|| format!("no piece layer for {:?}", file.path))?;
+        let count = file.piece_count(piece_length);
+        if layer.len() != count * 32 {
+            bail!(
+                "piece layer for {:?} has {} bytes, expected {}",
+                file.path,
+                layer.len(),
+                count * 32
+            );
+        }
+        let hashes: Vec<Hash> = layer
+            .chunks_exact(32)
+            .map(|chunk| chunk.try_into().expect("32-byte hash"))
+            .collect();
+        if merkle::root_from_piece_layer(&hashes, piece_length) != root {
+            bail!(
+                "piece layer for {:?} does not match its pieces root",
+                file.path
+            );
+        }
+        file.piece_layer = hashes;
+    }
+    Ok(())
+}
+
+/// The expected hash of piece `index` of `file`: an entry of its piece
+/// layer, or the pieces root for a file of a single piece. `None` when the
+/// piece layer is not known, e.g. for metadata fetched from a magnet link.
+pub fn expected_piece_hash(file: &V2File, index: usize, piece_length: usize) -> Option<Hash> {
+    if file.length <= piece_length {
+        (index == 0).then_some(file.pieces_root).flatten()
+    } else {
+        file.piece_layer.get(index).copied()
+    }
+}
+
+/// The hash of one piece of `file` as read from disk, comparable with
+/// [`expected_piece_hash`].
+pub fn piece_hash(file: &V2File, data: &[u8], piece_length: usize) -> Hash {
+    let leaves = merkle::block_hashes(data);
+    if file.length <= piece_length {
+        merkle::pieces_root(&leaves)
+    } else {
+        merkle::root_padded(&leaves, piece_length / BLOCK_SIZE, [0; 32])
+    }
+}
+
+/// Bencoded v2-only metainfo holding `files` below a directory `pkg`.
+#[cfg(test)]
+pub(crate) fn test_metainfo(files: &[(&str, &[u8])], piece_length: usize) -> Vec<u8> {
+    let mut layers = BTreeMap::new();
+    let files: Vec<V2File> = files
+        .iter()
+        .map(|(name, data)| {
+            let leaves = merkle::block_hashes(data);
+            let root = (!data.is_empty()).then(|| merkle::pieces_root(&leaves));
+            if data.len() > piece_length {
+                let layer = merkle::piece_layer(&leaves, piece_length).concat();
+                layers.insert(root.unwrap().to_vec(), BValue::Bytes(layer));
+            }
+            V2File {
+                path: vec![name.to_string()],
+                length: data.len(),
+                pieces_root: root,
+                piece_layer: Vec::new(),
+            }
+        })
+        .collect();
+    let info = BTreeMap::from([
+        (b"file tree".to_vec(), file_tree(&files)),
+        (b"meta version".to_vec(), BValue::Int(2)),
+        (b"name".to_vec(), BValue::Bytes(b"pkg".to_vec())),
+        (b"piece length".to_vec(), BValue::Int(piece_length as i64)),
+    ]);
+    BValue::Dict(BTreeMap::from([
+        (b"announce".to_vec(), BValue::Bytes(b"http://t".to_vec())),
+        (b"info".to_vec(), BValue::Dict(info)),
+        (b"piece layers".to_vec(), BValue::Dict(layers)),
+    ]))
+    .encode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::Bencode;
+
+    #[test]
+    fn file_tree_round_trip() {
+        let files = vec![
+            V2File {
+                path: vec!["a".into()],
+                length: 3,
+                pieces_root: Some([1; 32]),
+                piece_layer: Vec::new(),
+            },
+            V2File {
+                path: vec!["dir".into(), "b".into()],
+                length: 0,
+                pieces_root: None,
+                piece_layer: Vec::new(),
+            },
+        ];
+        let tree = file_tree(&files);
+        assert_eq!(parse_file_tree(&tree).unwrap(), files);
+
+        for bad in [
+            &b"de"[..],
+            b"d1:ad0:d6:lengthi3eeee",
+            b"d1:ad0:d6:lengthi0ee1:bdeee",
+            b"d0:d6:lengthi0eee",
+        ] {
+            assert!(parse_file_tree(&bad.bdecode().unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn piece_layers_are_checked() {
+        let piece_length = BLOCK_SIZE;
+        let data = vec![5u8; 3 * BLOCK_SIZE];
+        let leaves = merkle::block_hashes(&data);
+        let root = merkle::pieces_root(&leaves);
+        let layer = merkle::piece_layer(&leaves, piece_length);
+        let mut files = vec![V2File {
+            path: vec!["f".into()],
+            length: data.len(),
+            pieces_root: Some(root),
+            piece_layer: Vec::new(),
+        }];
+
+        let bytes: Vec<u8> = layer.concat();
+        let mut layers = BTreeMap::from([(ByteBuf::from(root.to_vec()), ByteBuf::from(bytes))]);
+        attach_piece_layers(&mut files, &layers, piece_length).unwrap();
+        assert_eq!(files[0].piece_layer, layer);
+        assert_eq!(
+            expected_piece_hash(&files[0], 2, piece_length),
+            Some(layer[2])
+        );
+        assert_eq!(
+            piece_hash(&files[0], &data[..BLOCK_SIZE], piece_length),
+            layer[0]
+        );
+
+        layers.values_mut().next().unwrap()[0] ^= 1;
+        assert!(attach_piece_layers(&mut files, &layers, piece_length).is_err());
+        assert!(attach_piece_layers(&mut files, &BTreeMap::new(), piece_length).is_err());
+        assert!(check_piece_length(3 * BLOCK_SIZE).is_err());
+    }
+}
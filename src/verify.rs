@@ -0,0 +1,459 @@
+//! # Verify
+//!
+//! check data on disk against a torrent's piece hashes
+//!
+
+use crate::torrent::{self, Layout, Torrent};
+use crate::v2::{self, MetaVersion};
+use anyhow::{bail, Context};
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// The outcome of checking one piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceStatus {
+    /// The data matches the piece hash.
+    Good,
+    /// The data is all there but does not match.
+    Bad,
+    /// A file covering the piece is absent or too short.
+    Missing,
+}
+
+/// Status of every piece, in piece order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub pieces: Vec<PieceStatus>,
+}
+
+impl VerifyReport {
+    /// Indices of the pieces with `status`.
+    pub fn indices(&self, status: PieceStatus) -> Vec<usize> {
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| **s == status)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    pub fn count(&self, status: PieceStatus) -> usize {
+        self.pieces.iter().filter(|s| **s == status).count()
+    }
+
+    /// Share of good pieces, from 0 to 100. A torrent without pieces is
+    /// complete.
+    pub fn percent_complete(&self) -> f64 {
+        if self.pieces.is_empty() {
+            return 100.0;
+        }
+        100.0 * self.count(PieceStatus::Good) as f64 / self.pieces.len() as f64
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(|s| *s == PieceStatus::Good)
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} good, {} bad, {} missing of {} pieces ({:.1}% complete)",
+            self.count(PieceStatus::Good),
+            self.count(PieceStatus::Bad),
+            self.count(PieceStatus::Missing),
+            self.pieces.len(),
+            self.percent_complete()
+        )
+    }
+}
+
+/// One entry of the v1 layout as found on disk.
+enum Part {
+    /// An open file and how many of its bytes are there.
+    File(File, usize),
+    Absent,
+    /// BEP 47 padding, which reads as zeros without being stored.
+    Padding,
+}
+
+/// Open a regular file and return how many bytes it holds. Anything that
+/// cannot be read as one, such as a directory, counts as absent.
+fn open_file(path: &Path) -> Option<(File, usize)> {
+    let file = File::open(path).ok()?;
+    let metadata = file.metadata().ok()?;
+    metadata
+        .is_file()
+        .then_some((file, metadata.len() as usize))
+}
+
+/// Read `buf.len()` bytes at `offset`, or `None` if the file fails to
+/// deliver them.
+fn read_at(file: &mut File, offset: usize, buf: &mut [u8]) -> Option<()> {
+    file.seek(SeekFrom::Start(offset as u64)).ok()?;
+    file.read_exact(buf).ok()
+}
+
+/// Hash the data stored at `root` (the file itself, or the top directory of
+/// a multi-file torrent) piece by piece against `torrent`.
+/// v2-only torrents are checked with [`verify_v2`].
+pub fn verify(torrent: &Torrent, root: &Path) -> anyhow::Result<VerifyReport> {
+    if !torrent.version.has_v1() {
+        return verify_v2(torrent, root);
+    }
+    let mut files = Vec::new();
+    for (path, length) in torrent.layout.file_paths(root)? {
+        let part = match path {
+            None => Part::Padding,
+            // a file shorter than the torrent says leaves its tail missing
+            Some(path) => match open_file(&path) {
+                Some((file, on_disk)) => Part::File(file, on_disk.min(length)),
+                None => Part::Absent,
+            },
+        };
+        files.push((part, length));
+    }
+
+    let mut pieces = Vec::with_capacity(torrent.piece_count());
+    let mut file_index = 0;
+    let mut file_start = 0;
+    for index in 0..torrent.piece_count() {
+        let start = index * torrent.piece_length;
+        let end = start + torrent.piece_len(index);
+        let mut data = Vec::with_capacity(end - start);
+        let mut missing = false;
+
+        // move past files that end before this piece
+        while file_index < files.len() && file_start + files[file_index].1 <= start {
+            file_start += files[file_index].1;
+            file_index += 1;
+        }
+        let (mut i, mut i_start) = (file_index, file_start);
+        while i < files.len() && i_start < end {
+            let (file, length) = &mut files[i];
+            let from = start.max(i_start) - i_start;
+            let to = end.min(i_start + *length) - i_start;
+            match file {
+                Part::File(file, available) if to <= *available => {
+                    let mut chunk = vec![0u8; to - from];
+                    match read_at(file, from, &mut chunk) {
+                        Some(()) => data.extend_from_slice(&chunk),
+                        None => missing = true,
+                    }
+                }
+                Part::Padding => data.resize(data.len() + to - from, 0),
+                _ => missing = true,
+            }
+            i_start += *length;
+            i += 1;
+        }
+
+        pieces.push(if missing {
+            PieceStatus::Missing
+        } else if torrent.verify_piece(index, &data) {
+            PieceStatus::Good
+        } else {
+            PieceStatus::Bad
+        });
+    }
+    Ok(VerifyReport { pieces })
+}
+
+/// Hash each file of the v2 `file tree` against its merkle tree. v2 pieces
+/// never span files, so the report lists every file's pieces in turn.
+/// Files that cannot be read leave their pieces missing; only problems in
+/// the torrent itself are errors.
+pub fn verify_v2(torrent: &Torrent, root: &Path) -> anyhow::Result<VerifyReport> {
+    let piece_length = torrent.piece_length;
+    let mut pieces = Vec::new();
+    for file in &torrent.v2_files {
+        let path = match torrent.layout {
+            Layout::SingleFile { .. } => root.to_path_buf(),
+            Layout::MultiFile { .. } => root.join(torrent::relative_path(&file.path)?),
+        };
+        let mut on_disk = open_file(&path);
+        for index in 0..file.piece_count(piece_length) {
+            let expected = v2::expected_piece_hash(file, index, piece_length)
+                .with_context(|| format!("no piece layer for {:?}", file.path))?;
+            let start = index * piece_length;
+            let end = file.length.min(start + piece_length);
+            pieces.push(match &mut on_disk {
+                Some((f, available)) if end <= *available => {
+                    let mut data = vec![0u8; end - start];
+                    match read_at(f, start, &mut data) {
+                        None => PieceStatus::Missing,
+                        Some(()) if v2::piece_hash(file, &data, piece_length) == expected => {
+                            PieceStatus::Good
+                        }
+                        Some(()) => PieceStatus::Bad,
+                    }
+                }
+                _ => PieceStatus::Missing,
+            });
+        }
+    }
+    Ok(VerifyReport { pieces })
+}
+
+/// A place where the v1 and v2 halves of a hybrid torrent disagree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// The v1 `files` and the v2 `file tree` describe different files, or a
+    /// v1 file does not start on a piece boundary.
+    Layout(String),
+    /// The two hash trees judge the same piece of a file differently.
+    Piece {
+        path: Vec<String>,
+        index: usize,
+        v1: PieceStatus,
+        v2: PieceStatus,
+    },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inconsistency::Layout(problem) => write!(f, "{}", problem),
+            Inconsistency::Piece {
+                path,
+                index,
+                v1,
+                v2,
+            } => write!(
+                f,
+                "piece {} of {}: v1 {:?}, v2 {:?}",
+                index,
+                path.join("/"),
+                v1,
+                v2
+            ),
+        }
+    }
+}
+
+/// Both hash trees of a hybrid torrent checked against the same data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HybridReport {
+    pub v1: VerifyReport,
+    pub v2: VerifyReport,
+    pub inconsistencies: Vec<Inconsistency>,
+}
+
+impl HybridReport {
+    pub fn is_complete(&self) -> bool {
+        self.v1.is_complete() && self.v2.is_complete()
+    }
+
+    pub fn is_consistent(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
+}
+
+/// Check a hybrid torrent with both [`verify`] and [`verify_v2`]. Since
+/// padding puts every v1 file on a piece boundary, each v1 piece holds
+/// exactly one v2 piece and their statuses must agree.
+pub fn verify_hybrid(torrent: &Torrent, root: &Path) -> anyhow::Result<HybridReport> {
+    if torrent.version != MetaVersion::Hybrid {
+        bail!("the torrent is not a hybrid");
+    }
+    let v1 = verify(torrent, root)?;
+    let v2 = verify_v2(torrent, root)?;
+    let piece_length = torrent.piece_length;
+    let mut inconsistencies = Vec::new();
+
+    // v1 files with the index of their first piece, leaving out padding
+    let v1_files: Vec<(Vec<String>, usize, usize)> = match &torrent.layout {
+        Layout::SingleFile { length, .. } => vec![(vec![torrent.name.clone()], *length, 0)],
+        Layout::MultiFile { files } => {
+            let mut offset = 0;
+            let mut v1_files = Vec::new();
+            for file in files {
+                if !file.is_padding() {
+                    if file.length > 0 && offset % piece_length != 0 {
+                        inconsistencies.push(Inconsistency::Layout(format!(
+                            "{} does not start on a piece boundary",
+                            file.lossy_path().join("/")
+                        )));
+                    }
+                    v1_files.push((file.lossy_path(), file.length, offset / piece_length));
+                }
+                offset += file.length;
+            }
+            v1_files
+        }
+    };
+    if v1_files.len() != torrent.v2_files.len() {
+        inconsistencies.push(Inconsistency::Layout(format!(
+            "v1 lists {} files but the v2 file tree {}",
+            v1_files.len(),
+            torrent.v2_files.len()
+        )));
+    }
+
+    let mut v2_start = 0;
+    for ((path, length, v1_start), file) in v1_files.iter().zip(&torrent.v2_files) {
+        let count = file.piece_count(piece_length);
+        if *path != file.path || *length != file.length {
+            inconsistencies.push(Inconsistency::Layout(format!(
+                "v1 has {} ({} bytes) where v2 has {} ({} bytes)",
+                path.join("/"),
+                length,
+                file.path.join("/"),
+                file.length
+            )));
+        } else if inconsistencies.is_empty() {
+            for index in 0..count {
+                let (v1, v2) = (v1.pieces[v1_start + index], v2.pieces[v2_start + index]);
+                if v1 != v2 {
+                    inconsistencies.push(Inconsistency::Piece {
+                        path: path.clone(),
+                        index,
+                        v1,
+                        v2,
+                    });
+                }
+            }
+        }
+        v2_start += count;
+    }
+    Ok(HybridReport {
+        v1,
+        v2,
+        inconsistencies,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode;
+    use crate::create::{create, CreateOptions};
+    use std::fs;
+
+    #[test]
+    fn good_bad_and_missing_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("pkg");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a"), b"aaaaaa").unwrap();
+        fs::write(root.join("b"), b"bbbbbb").unwrap();
+        fs::write(root.join("c"), b"cc").unwrap();
+        let options = CreateOptions {
+            piece_length: Some(4),
+            ..CreateOptions::default()
+        };
+        let torrent = Torrent::from_bytes(&create(&root, &options).unwrap()).unwrap();
+
+        let report = verify(&torrent, &root).unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.percent_complete(), 100.0);
+
+        // pieces: aaaa | aabb | bbbb | cc
+        fs::write(root.join("b"), b"bbbbbx").unwrap();
+        fs::remove_file(root.join("c")).unwrap();
+        let report = verify(&torrent, &root).unwrap();
+        assert_eq!(
+            report.pieces,
+            [
+                PieceStatus::Good,
+                PieceStatus::Good,
+                PieceStatus::Bad,
+                PieceStatus::Missing
+            ]
+        );
+        assert_eq!(report.indices(PieceStatus::Bad), [2]);
+        assert!(!report.is_complete());
+        assert_eq!(
+            report.to_string(),
+            "2 good, 1 bad, 1 missing of 4 pieces (50.0% complete)"
+        );
+
+        fs::write(root.join("a"), b"aaaaa").unwrap();
+        let report = verify(&torrent, &root).unwrap();
+        assert_eq!(report.pieces[0], PieceStatus::Good);
+        assert_eq!(report.pieces[1], PieceStatus::Missing);
+
+        // a directory where a file should be is missing, not an error
+        fs::create_dir(root.join("c")).unwrap();
+        let report = verify(&torrent, &root).unwrap();
+        assert_eq!(report.pieces[3], PieceStatus::Missing);
+    }
+
+    #[test]
+    fn v2_files_are_checked_against_their_merkle_trees() {
+        use crate::merkle::BLOCK_SIZE;
+        let big = vec![7u8; 2 * BLOCK_SIZE + 5];
+        let bytes = v2::test_metainfo(&[("big", &big), ("small", b"abc")], BLOCK_SIZE);
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("big"), &big).unwrap();
+        fs::write(dir.path().join("small"), b"abc").unwrap();
+        let report = verify(&torrent, dir.path()).unwrap();
+        assert_eq!(report.pieces.len(), 4);
+        assert!(report.is_complete());
+
+        let mut changed = big.clone();
+        changed[BLOCK_SIZE] = 0;
+        fs::write(dir.path().join("big"), &changed).unwrap();
+        fs::remove_file(dir.path().join("small")).unwrap();
+        fs::create_dir(dir.path().join("small")).unwrap();
+        let report = verify(&torrent, dir.path()).unwrap();
+        assert_eq!(
+            report.pieces,
+            [
+                PieceStatus::Good,
+                PieceStatus::Bad,
+                PieceStatus::Good,
+                PieceStatus::Missing
+            ]
+        );
+    }
+
+    #[test]
+    fn hybrid_trees_are_compared() {
+        use crate::merkle::BLOCK_SIZE;
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("pkg");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a"), b"aaa").unwrap();
+        fs::write(root.join("b"), vec![2u8; BLOCK_SIZE + 1]).unwrap();
+        let options = CreateOptions {
+            piece_length: Some(BLOCK_SIZE),
+            hybrid: true,
+            ..CreateOptions::default()
+        };
+        let bytes = create(&root, &options).unwrap();
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+
+        fs::write(root.join("b"), vec![0u8; BLOCK_SIZE + 1]).unwrap();
+        let report = verify_hybrid(&torrent, &root).unwrap();
+        assert_eq!(report.v1.indices(PieceStatus::Bad), [1, 2]);
+        assert_eq!(report.v2.indices(PieceStatus::Bad), [1, 2]);
+        assert!(report.is_consistent());
+        assert!(!report.is_complete());
+
+        // a v1 hash that no longer matches data the v2 tree accepts
+        fs::write(root.join("b"), vec![2u8; BLOCK_SIZE + 1]).unwrap();
+        let mut meta = torrent.meta.clone();
+        meta.info.pieces.as_mut().unwrap()[20] ^= 1;
+        let torrent = Torrent::from_bytes(&bencode::to_bytes(&meta).unwrap()).unwrap();
+        let report = verify_hybrid(&torrent, &root).unwrap();
+        assert_eq!(
+            report.inconsistencies,
+            [Inconsistency::Piece {
+                path: vec!["b".into()],
+                index: 0,
+                v1: PieceStatus::Bad,
+                v2: PieceStatus::Good,
+            }]
+        );
+        assert_eq!(
+            report.inconsistencies[0].to_string(),
+            "piece 0 of b: v1 Bad, v2 Good"
+        );
+    }
+}